@@ -15,6 +15,14 @@ const char* mqtt_server = MQTT_SERVER;
 const int mqtt_port = MQTT_PORT;
 const char* mqtt_username = MQTT_USERNAME;
 const char* mqtt_password = MQTT_PASSWORD;
+// Topic to publish readings on, e.g. "sensors/livingroom". The RabbitMQ MQTT
+// plugin forwards it to amq.topic with the routing key "sensors.livingroom".
+#ifndef MQTT_TOPIC
+#define MQTT_TOPIC "opla"
+#endif
+const char* mqtt_topic = MQTT_TOPIC;
+// Token printed by `backend device add <mac>`
+const char* device_token = DEVICE_TOKEN;
 char clientId[18];
 
 WiFiClient wifiClient;
@@ -74,6 +82,14 @@ uint16_t getHumidityColor(float humidity) {
     return carrier.display.color565(red, green, blue);
 }
 
+// Append a named measurement to the metrics array of the payload
+void addMetric(JsonArray metrics, const char* name, float value, const char* unit) {
+    JsonObject metric = metrics.createNestedObject();
+    metric["name"] = name;
+    metric["value"] = value;
+    metric["unit"] = unit;
+}
+
 void setup() {
     Serial.begin(115200);
     while (!Serial);
@@ -98,9 +114,15 @@ void loop() {
     unsigned long timestamp = timeClient.getEpochTime(); // Get Unix timestamp
 
     float humidity = carrier.Env.readHumidity();
+    float temperature = carrier.Env.readTemperature();
+    float pressure = carrier.Pressure.readPressure();
+    float gasResistance = carrier.AirQuality.readGasResistor();
     Serial.print("Humidity: ");
     Serial.print(humidity);
     Serial.println(" %");
+    Serial.print("Temperature: ");
+    Serial.print(temperature);
+    Serial.println(" C");
 
     // Get color based on humidity percentage
     uint16_t bgColor = getHumidityColor(humidity);
@@ -121,17 +143,30 @@ void loop() {
     carrier.display.print(clientId);
     carrier.display.println(".");
 
+#ifdef DEVICE_CLAIM_CODE
+    // Display the claim code the owner enters when adding the sensor
+    carrier.display.setCursor(20, 160);
+    carrier.display.print("Claim: ");
+    carrier.display.print(DEVICE_CLAIM_CODE);
+#endif
+
     // Create JSON payload using StaticJsonDocument
-    StaticJsonDocument<256> jsonDoc;
+    StaticJsonDocument<512> jsonDoc;
     jsonDoc["mac"] = clientId;
-    jsonDoc["humidity"] = humidity;
     jsonDoc["timestamp"] = timestamp; // Add Unix timestamp
+    jsonDoc["token"] = device_token;
+
+    JsonArray metrics = jsonDoc.createNestedArray("metrics");
+    addMetric(metrics, "humidity", humidity, "%");
+    addMetric(metrics, "temperature", temperature, "°C");
+    addMetric(metrics, "pressure", pressure, "kPa");
+    addMetric(metrics, "gas_resistance", gasResistance, "Ω");
 
-    char jsonBuffer[256];
+    char jsonBuffer[512];
     serializeJson(jsonDoc, jsonBuffer);  // Convert JSON object to a string
 
     // Publish MQTT message
-    if (client.publish("opla", jsonBuffer)) {
+    if (client.publish(mqtt_topic, jsonBuffer)) {
         Serial.println("Data sent successfully!");
     } else {
         sentErrorMessage("Failed to send data");
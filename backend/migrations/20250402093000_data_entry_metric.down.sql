@@ -0,0 +1,17 @@
+-- Add down migration script here
+
+-- 1. Restore the single value column from the humidity metric
+ALTER TABLE data_entry ADD COLUMN value DOUBLE PRECISION;
+
+UPDATE data_entry de
+SET value = dem.value
+FROM data_entry_metric dem
+WHERE dem.data_entry_id = de.id AND dem.name = 'humidity';
+
+-- 2. Readings without humidity cannot be represented in the old schema
+DELETE FROM data_entry WHERE value IS NULL;
+
+ALTER TABLE data_entry ALTER COLUMN value SET NOT NULL;
+
+-- 3. Drop the metric table
+DROP TABLE IF EXISTS data_entry_metric;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+
+-- 1. Create the metric table, one row per named measurement in a reading
+CREATE TABLE data_entry_metric (
+    id SERIAL PRIMARY KEY,
+    data_entry_id INTEGER NOT NULL REFERENCES data_entry(id) ON DELETE CASCADE,
+    name VARCHAR(50) NOT NULL,
+    value DOUBLE PRECISION NOT NULL,
+    unit VARCHAR(16),
+    CONSTRAINT unique_data_entry_metric UNIQUE (data_entry_id, name)
+);
+
+-- 2. Move the existing humidity values into the metric table
+INSERT INTO data_entry_metric (data_entry_id, name, value, unit)
+SELECT id, 'humidity', value, '%' FROM data_entry;
+
+-- 3. Drop the single value column
+ALTER TABLE data_entry DROP COLUMN value;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+
+CREATE TABLE rejected_message (
+    id SERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    replayed_at TIMESTAMP WITH TIME ZONE
+);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+
+ALTER TABLE data_entry DROP CONSTRAINT unique_identifier_created_at;
@@ -0,0 +1,11 @@
+-- Add up migration script here
+
+-- 1. Remove duplicate readings, keeping the first one that was stored
+DELETE FROM data_entry a
+USING data_entry b
+WHERE a.unique_identifier = b.unique_identifier
+    AND a.created_at = b.created_at
+    AND a.id > b.id;
+
+-- 2. A device can only report one reading per timestamp, so redelivery is idempotent
+ALTER TABLE data_entry ADD CONSTRAINT unique_identifier_created_at UNIQUE (unique_identifier, created_at);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+ALTER TABLE rejected_message DROP COLUMN topic;
+ALTER TABLE data_entry DROP COLUMN topic;
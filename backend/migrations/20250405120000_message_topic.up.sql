@@ -0,0 +1,5 @@
+-- Add up migration script here
+
+-- Routing key (or MQTT topic) the reading arrived on
+ALTER TABLE data_entry ADD COLUMN topic VARCHAR(255);
+ALTER TABLE rejected_message ADD COLUMN topic VARCHAR(255);
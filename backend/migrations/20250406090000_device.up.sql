@@ -0,0 +1,11 @@
+-- Add up migration script here
+
+-- Registered sensors and the hash of the token they authenticate with
+CREATE TABLE device (
+    id SERIAL PRIMARY KEY,
+    unique_identifier VARCHAR(25) UNIQUE NOT NULL,
+    token_hash VARCHAR(64) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP WITH TIME ZONE,
+    rejected_count INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+ALTER TABLE device DROP COLUMN owner_id;
+ALTER TABLE device DROP COLUMN claim_code_hash;
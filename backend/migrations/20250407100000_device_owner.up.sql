@@ -0,0 +1,7 @@
+-- Add up migration script here
+
+-- 1. Claim code (hashed) that proves physical access to a device
+ALTER TABLE device ADD COLUMN claim_code_hash TEXT;
+
+-- 2. The single account that owns the device
+ALTER TABLE device ADD COLUMN owner_id INTEGER REFERENCES app_user(id) ON DELETE SET NULL;
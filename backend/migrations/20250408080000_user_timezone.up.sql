@@ -0,0 +1,4 @@
+-- Add up migration script here
+
+-- IANA timezone that day and week aggregates are computed in
+ALTER TABLE app_user ADD COLUMN timezone VARCHAR(64) NOT NULL DEFAULT 'UTC';
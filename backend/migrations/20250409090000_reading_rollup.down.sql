@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS rollup_state;
+DROP TABLE IF EXISTS reading_rollup_daily;
+DROP TABLE IF EXISTS reading_rollup_hourly;
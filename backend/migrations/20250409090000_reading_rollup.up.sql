@@ -0,0 +1,38 @@
+-- Add up migration script here
+
+-- 1. Hourly and daily aggregates of each metric, kept after raw readings expire.
+-- The sum is stored instead of the average so partial aggregates can be merged.
+CREATE TABLE reading_rollup_hourly (
+    unique_identifier VARCHAR(25) NOT NULL,
+    bucket TIMESTAMP WITH TIME ZONE NOT NULL,
+    name VARCHAR(50) NOT NULL,
+    unit VARCHAR(16),
+    min DOUBLE PRECISION NOT NULL,
+    max DOUBLE PRECISION NOT NULL,
+    sum DOUBLE PRECISION NOT NULL,
+    count BIGINT NOT NULL,
+    PRIMARY KEY (unique_identifier, bucket, name)
+);
+
+-- Daily buckets start at midnight UTC
+CREATE TABLE reading_rollup_daily (
+    unique_identifier VARCHAR(25) NOT NULL,
+    bucket TIMESTAMP WITH TIME ZONE NOT NULL,
+    name VARCHAR(50) NOT NULL,
+    unit VARCHAR(16),
+    min DOUBLE PRECISION NOT NULL,
+    max DOUBLE PRECISION NOT NULL,
+    sum DOUBLE PRECISION NOT NULL,
+    count BIGINT NOT NULL,
+    PRIMARY KEY (unique_identifier, bucket, name)
+);
+
+-- 2. How far the rollup job got: readings up to last_entry_id are included, and
+-- hours before rolled_until are complete
+CREATE TABLE rollup_state (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    last_entry_id INTEGER NOT NULL DEFAULT 0,
+    rolled_until TIMESTAMP WITH TIME ZONE
+);
+
+INSERT INTO rollup_state DEFAULT VALUES;
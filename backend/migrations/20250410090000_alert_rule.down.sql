@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS alert_event;
+DROP TABLE IF EXISTS alert_rule;
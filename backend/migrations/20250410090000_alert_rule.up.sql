@@ -0,0 +1,35 @@
+-- Add up migration script here
+
+-- 1. Rules users define on their sensors, with the state of their evaluation
+CREATE TABLE alert_rule (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES app_user(id) ON DELETE CASCADE,
+    unique_identifier VARCHAR(25) NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    metric VARCHAR(50) NOT NULL DEFAULT 'humidity',
+    condition VARCHAR(16) NOT NULL,
+    threshold DOUBLE PRECISION NOT NULL,
+    hysteresis DOUBLE PRECISION NOT NULL DEFAULT 0,
+    duration_seconds INTEGER NOT NULL DEFAULT 0,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    state VARCHAR(16) NOT NULL DEFAULT 'ok',
+    state_since TIMESTAMP WITH TIME ZONE,
+    last_value DOUBLE PRECISION,
+    -- Time of the newest reading evaluated, older ones are ignored
+    evaluated_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_alert_rule_identifier ON alert_rule (unique_identifier) WHERE enabled;
+
+-- 2. History of alerts firing and resolving
+CREATE TABLE alert_event (
+    id SERIAL PRIMARY KEY,
+    rule_id INTEGER NOT NULL REFERENCES alert_rule(id) ON DELETE CASCADE,
+    kind VARCHAR(16) NOT NULL,
+    value DOUBLE PRECISION,
+    message TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX idx_alert_event_rule ON alert_event (rule_id, created_at);
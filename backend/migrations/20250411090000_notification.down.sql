@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS notification_delivery;
+DROP TABLE IF EXISTS alert_rule_channel;
+DROP TABLE IF EXISTS notification_channel;
@@ -0,0 +1,36 @@
+-- Add up migration script here
+
+-- 1. Where a user wants to be notified, e.g. a webhook or an email address
+CREATE TABLE notification_channel (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES app_user(id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    config JSONB NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- 2. Channels an alert rule notifies
+CREATE TABLE alert_rule_channel (
+    rule_id INTEGER NOT NULL REFERENCES alert_rule(id) ON DELETE CASCADE,
+    channel_id INTEGER NOT NULL REFERENCES notification_channel(id) ON DELETE CASCADE,
+    PRIMARY KEY (rule_id, channel_id)
+);
+
+-- 3. Every notification sent or still to be sent, with its retries
+CREATE TABLE notification_delivery (
+    id SERIAL PRIMARY KEY,
+    channel_id INTEGER NOT NULL REFERENCES notification_channel(id) ON DELETE CASCADE,
+    -- Empty for test notifications
+    event_id INTEGER REFERENCES alert_event(id) ON DELETE SET NULL,
+    payload JSONB NOT NULL,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    delivered_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_notification_delivery_due ON notification_delivery (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX idx_notification_delivery_channel ON notification_delivery (channel_id, created_at);
@@ -0,0 +1,7 @@
+-- Add down migration script here
+
+DELETE FROM alert_rule WHERE condition IN ('rises', 'falls');
+
+ALTER TABLE alert_rule DROP COLUMN last_fired_at;
+ALTER TABLE alert_rule DROP COLUMN cooldown_seconds;
+ALTER TABLE alert_rule DROP COLUMN window_seconds;
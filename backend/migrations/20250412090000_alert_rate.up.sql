@@ -0,0 +1,8 @@
+-- Add up migration script here
+
+-- 1. Rate-of-change rules compare each reading to the others in a sliding window
+ALTER TABLE alert_rule ADD COLUMN window_seconds INTEGER NOT NULL DEFAULT 0;
+
+-- 2. How long a rule stays quiet after firing, and when it last fired
+ALTER TABLE alert_rule ADD COLUMN cooldown_seconds INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE alert_rule ADD COLUMN last_fired_at TIMESTAMP WITH TIME ZONE;
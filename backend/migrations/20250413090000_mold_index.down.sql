@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS mold_state;
+DROP TABLE IF EXISTS mold_index;
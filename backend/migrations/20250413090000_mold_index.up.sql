@@ -0,0 +1,27 @@
+-- Add up migration script here
+
+-- 1. Mold index of each sensor by hour, with the climate it was computed from.
+-- Hours without humidity readings are left out.
+CREATE TABLE mold_index (
+    unique_identifier VARCHAR(25) NOT NULL,
+    hour TIMESTAMP WITH TIME ZONE NOT NULL,
+    value DOUBLE PRECISION NOT NULL,
+    humidity DOUBLE PRECISION NOT NULL,
+    -- Measured, or the configured default when the sensor has no temperature
+    temperature DOUBLE PRECISION NOT NULL,
+    -- Whether the hour was humid and warm enough for mold to grow
+    favourable BOOLEAN NOT NULL,
+    PRIMARY KEY (unique_identifier, hour)
+);
+
+-- 2. Where the model of each sensor stopped, to continue from there
+CREATE TABLE mold_state (
+    unique_identifier VARCHAR(25) PRIMARY KEY,
+    mold_index DOUBLE PRECISION NOT NULL DEFAULT 0,
+    -- Hours since conditions last favoured growth
+    dry_hours INTEGER NOT NULL DEFAULT 0,
+    computed_until TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- Newest reading seen, so readings arriving late for hours already
+    -- computed can be told apart
+    last_entry_id INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+-- Derived metrics are left in place, as they cannot be told apart from ones
+-- the sensors reported themselves
+ALTER TABLE data_entry_mapping DROP COLUMN IF EXISTS surface_temperature;
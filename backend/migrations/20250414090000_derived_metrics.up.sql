@@ -0,0 +1,69 @@
+-- Add up migration script here
+
+-- 1. Temperature of the coldest surface in a sensor's room, like a window or
+-- an outer wall, to tell when water condenses on it
+ALTER TABLE data_entry_mapping ADD COLUMN surface_temperature DOUBLE PRECISION;
+
+-- 2. Derive dew point, absolute humidity and humidex for stored readings with
+-- both humidity and temperature, as new readings get them on ingest
+CREATE TEMPORARY TABLE derived_metric AS
+WITH climate AS (
+    SELECT h.data_entry_id, t.value as temperature, h.value as humidity,
+        243.12 * (ln(h.value / 100) + 17.62 * t.value / (243.12 + t.value))
+            / (17.62 - (ln(h.value / 100) + 17.62 * t.value / (243.12 + t.value))) as dew_point
+    FROM data_entry_metric h
+    JOIN data_entry_metric t ON t.data_entry_id = h.data_entry_id AND t.name = 'temperature'
+    WHERE h.name = 'humidity'
+    AND COALESCE(h.unit, '%') = '%' AND COALESCE(t.unit, '°C') = '°C'
+    AND h.value > 0 AND h.value <= 100 AND t.value > -243.12
+),
+derived AS (
+    SELECT data_entry_id, 'dew_point' as name, dew_point as value, '°C' as unit
+    FROM climate
+    UNION ALL
+    SELECT data_entry_id, 'absolute_humidity',
+        216.7 * 6.112 * exp(17.62 * temperature / (243.12 + temperature)) * humidity / 100 / (273.15 + temperature),
+        'g/m³'
+    FROM climate
+    UNION ALL
+    SELECT data_entry_id, 'humidex',
+        temperature + 0.5555 * (6.11 * exp(5417.7530 * (1 / 273.16 - 1 / (273.15 + dew_point))) - 10),
+        NULL
+    FROM climate
+)
+SELECT d.* FROM derived d
+WHERE NOT EXISTS (
+    SELECT 1 FROM data_entry_metric m WHERE m.data_entry_id = d.data_entry_id AND m.name = d.name
+);
+
+INSERT INTO data_entry_metric (data_entry_id, name, value, unit)
+SELECT data_entry_id, name, value, unit FROM derived_metric;
+
+-- 3. Add them to the rollups of readings that are already rolled up
+INSERT INTO reading_rollup_hourly AS r (unique_identifier, bucket, name, unit, min, max, sum, count)
+SELECT de.unique_identifier, date_trunc('hour', de.created_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC',
+    dm.name, MAX(dm.unit), MIN(dm.value), MAX(dm.value), SUM(dm.value), COUNT(*)
+FROM derived_metric dm
+JOIN data_entry de ON de.id = dm.data_entry_id
+WHERE de.id <= (SELECT last_entry_id FROM rollup_state)
+GROUP BY 1, 2, 3
+ON CONFLICT (unique_identifier, bucket, name) DO UPDATE SET
+    min = LEAST(r.min, EXCLUDED.min),
+    max = GREATEST(r.max, EXCLUDED.max),
+    sum = r.sum + EXCLUDED.sum,
+    count = r.count + EXCLUDED.count;
+
+INSERT INTO reading_rollup_daily AS r (unique_identifier, bucket, name, unit, min, max, sum, count)
+SELECT de.unique_identifier, date_trunc('day', de.created_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC',
+    dm.name, MAX(dm.unit), MIN(dm.value), MAX(dm.value), SUM(dm.value), COUNT(*)
+FROM derived_metric dm
+JOIN data_entry de ON de.id = dm.data_entry_id
+WHERE de.id <= (SELECT last_entry_id FROM rollup_state)
+GROUP BY 1, 2, 3
+ON CONFLICT (unique_identifier, bucket, name) DO UPDATE SET
+    min = LEAST(r.min, EXCLUDED.min),
+    max = GREATEST(r.max, EXCLUDED.max),
+    sum = r.sum + EXCLUDED.sum,
+    count = r.count + EXCLUDED.count;
+
+DROP TABLE derived_metric;
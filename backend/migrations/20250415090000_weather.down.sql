@@ -0,0 +1,8 @@
+-- Add down migration script here
+
+-- Readings of the virtual sensors are kept, but they are no longer mapped
+DELETE FROM data_entry_mapping WHERE unique_identifier LIKE 'weather:%';
+
+DROP TABLE IF EXISTS weather_forecast;
+DROP TABLE IF EXISTS user_location;
+DROP TABLE IF EXISTS weather_location;
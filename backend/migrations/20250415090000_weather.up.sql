@@ -0,0 +1,28 @@
+-- Add up migration script here
+
+-- 1. Places weather is fetched for, each stored as a virtual sensor. Users close
+-- to each other share one, as coordinates are rounded to about a kilometre.
+CREATE TABLE weather_location (
+    unique_identifier VARCHAR(25) PRIMARY KEY,
+    latitude DOUBLE PRECISION NOT NULL,
+    longitude DOUBLE PRECISION NOT NULL,
+    fetched_at TIMESTAMP WITH TIME ZONE,
+    -- Why the last fetch failed, empty after a successful one
+    last_error TEXT
+);
+
+-- 2. Where each user is
+CREATE TABLE user_location (
+    user_id INTEGER PRIMARY KEY REFERENCES app_user(id) ON DELETE CASCADE,
+    unique_identifier VARCHAR(25) NOT NULL REFERENCES weather_location(unique_identifier),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- 3. Hourly forecast of each location from the last fetch
+CREATE TABLE weather_forecast (
+    unique_identifier VARCHAR(25) NOT NULL REFERENCES weather_location(unique_identifier) ON DELETE CASCADE,
+    time TIMESTAMP WITH TIME ZONE NOT NULL,
+    temperature DOUBLE PRECISION NOT NULL,
+    humidity DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (unique_identifier, time)
+);
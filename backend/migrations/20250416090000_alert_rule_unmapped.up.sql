@@ -0,0 +1,9 @@
+-- Add up migration script here
+
+-- 1. Rules on sensors their user no longer has mapped, left behind by claims,
+-- transfers and deleted mappings, would keep reporting another user's values
+DELETE FROM alert_rule ar
+WHERE NOT EXISTS (
+    SELECT 1 FROM data_entry_mapping dem
+    WHERE dem.unique_identifier = ar.unique_identifier AND dem.user_id = ar.user_id
+);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+
+-- Provisional owners are kept, as they cannot be told apart from claims
@@ -0,0 +1,14 @@
+-- Add up migration script here
+
+-- 1. Registered devices nobody claimed yet go to the household that mapped them,
+-- as long as only one did. Sensors mapped by several households stay mapped by
+-- all of them until one claims it, and no mapping is removed here.
+UPDATE device d
+SET owner_id = mapped.user_id
+FROM (
+    SELECT unique_identifier, MIN(user_id) AS user_id
+    FROM data_entry_mapping
+    GROUP BY unique_identifier
+    HAVING COUNT(DISTINCT user_id) = 1
+) mapped
+WHERE d.unique_identifier = mapped.unique_identifier AND d.owner_id IS NULL;
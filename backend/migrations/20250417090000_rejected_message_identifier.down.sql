@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS idx_rejected_message_identifier;
+ALTER TABLE rejected_message DROP COLUMN unique_identifier;
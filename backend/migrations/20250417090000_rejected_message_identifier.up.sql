@@ -0,0 +1,25 @@
+-- Add up migration script here
+
+-- 1. The device a quarantined message claims to be from, so its owner can see it.
+-- Unset when the payload does not name one.
+ALTER TABLE rejected_message ADD COLUMN unique_identifier TEXT;
+
+-- 2. Fill it in for messages quarantined so far, skipping payloads that are not JSON
+DO $$
+DECLARE
+    message RECORD;
+BEGIN
+    FOR message IN SELECT id, payload FROM rejected_message LOOP
+        BEGIN
+            UPDATE rejected_message
+            SET unique_identifier = message.payload::jsonb ->> 'mac'
+            WHERE id = message.id;
+        EXCEPTION WHEN invalid_text_representation THEN
+            NULL;
+        END;
+    END LOOP;
+END
+$$;
+
+-- 3. Owners look their messages up by device
+CREATE INDEX idx_rejected_message_identifier ON rejected_message (unique_identifier);
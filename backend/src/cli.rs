@@ -0,0 +1,235 @@
+use sqlx::{Pool, Postgres};
+use tokio::io::AsyncReadExt;
+
+use crate::core::import::{ImportFormat, Importer};
+use crate::core::ingest::{self, IngestOutcome};
+use crate::core::stream;
+use crate::models::device;
+use crate::models::rejected_message::{self, RejectedMessage};
+
+const USAGE: &str = "Usage:
+  backend                               Start the server
+  backend device add <identifier>       Register a device and print its token
+  backend device rotate <identifier>    Issue a new token for a device
+  backend device claim-code <identifier>
+                                        Issue a new claim code for a device
+  backend device release <identifier>   Remove the owner so the device can be claimed again
+  backend device remove <identifier>    Remove a device from the registry
+  backend device list                   List registered devices
+  backend import <file> [csv|ndjson]    Import readings of registered devices from a file
+  backend rejected list [all]           List quarantined messages, with replayed ones on `all`
+  backend rejected show <id>            Print a quarantined message
+  backend rejected replay <id>          Ingest a quarantined message again";
+
+// Run an admin command instead of the server
+pub async fn run(db: &Pool<Postgres>, args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["device", "add", identifier] => {
+            let token = device::generate_token();
+            let claim_code = device::generate_claim_code();
+            device::create(
+                db,
+                identifier,
+                &device::hash_token(&token),
+                &device::hash_claim_code(&claim_code),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            println!("Registered device {}", identifier);
+            println!("Token: {}", token);
+            println!("Claim code: {}", claim_code);
+        }
+        ["device", "rotate", identifier] => {
+            let token = device::generate_token();
+            match device::update_token(db, identifier, &device::hash_token(&token))
+                .await
+                .map_err(|e| e.to_string())?
+            {
+                Some(_) => {
+                    println!("Issued new token for device {}", identifier);
+                    println!("Token: {}", token);
+                }
+                None => return Err(format!("Device {} is not registered", identifier)),
+            }
+        }
+        ["device", "claim-code", identifier] => {
+            let claim_code = device::generate_claim_code();
+            match device::update_claim_code(db, identifier, &device::hash_claim_code(&claim_code))
+                .await
+                .map_err(|e| e.to_string())?
+            {
+                Some(_) => {
+                    println!("Issued new claim code for device {}", identifier);
+                    println!("Claim code: {}", claim_code);
+                }
+                None => return Err(format!("Device {} is not registered", identifier)),
+            }
+        }
+        ["device", "release", identifier] => {
+            if !device::release(db, identifier)
+                .await
+                .map_err(|e| e.to_string())?
+            {
+                return Err(format!("Device {} is not registered", identifier));
+            }
+            println!("Released device {}", identifier);
+        }
+        ["device", "remove", identifier] => {
+            if !device::delete(db, identifier)
+                .await
+                .map_err(|e| e.to_string())?
+            {
+                return Err(format!("Device {} is not registered", identifier));
+            }
+            println!("Removed device {}", identifier);
+        }
+        ["device", "list"] => {
+            let devices = device::get_all(db).await.map_err(|e| e.to_string())?;
+            println!(
+                "{:<25} {:<8} {:<25} {:<25} REJECTED",
+                "IDENTIFIER", "OWNER", "REGISTERED", "LAST SEEN"
+            );
+            for device in devices {
+                println!(
+                    "{:<25} {:<8} {:<25} {:<25} {}",
+                    device.unique_identifier,
+                    device
+                        .owner_id
+                        .map(|owner_id| owner_id.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    device.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    device
+                        .last_seen_at
+                        .map(|seen| seen.format("%Y-%m-%d %H:%M:%S").to_string())
+                        .unwrap_or_else(|| "never".to_string()),
+                    device.rejected_count
+                );
+            }
+        }
+        ["import", path] => import(db, path, ImportFormat::detect(path)).await?,
+        ["import", path, format] => import(db, path, ImportFormat::detect(format)).await?,
+        ["rejected", "list"] => list_rejected(db, false).await?,
+        ["rejected", "list", "all"] => list_rejected(db, true).await?,
+        ["rejected", "show", id] => {
+            let message = get_rejected(db, id).await?;
+            println!("Id: {}", message.id);
+            println!("Received: {}", message.created_at.format("%Y-%m-%d %H:%M:%S"));
+            println!(
+                "Device: {}",
+                message.unique_identifier.as_deref().unwrap_or("-")
+            );
+            println!("Topic: {}", message.topic.as_deref().unwrap_or("-"));
+            println!("Reason: {}", message.reason);
+            if let Some(replayed_at) = message.replayed_at {
+                println!("Replayed: {}", replayed_at.format("%Y-%m-%d %H:%M:%S"));
+            }
+            println!("{}", message.payload);
+        }
+        ["rejected", "replay", id] => replay_rejected(db, id).await?,
+        _ => return Err(USAGE.to_string()),
+    }
+
+    Ok(())
+}
+
+// Import readings from a file, for any registered device
+async fn import(db: &Pool<Postgres>, path: &str, format: Option<ImportFormat>) -> Result<(), String> {
+    let format = format.ok_or("Unknown format, pass csv or ndjson")?;
+    let allowed = device::get_all(db)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|device| device.unique_identifier)
+        .collect();
+
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| format!("Cannot open {}: {}", path, e))?;
+    let mut importer = Importer::new(db, format, allowed);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        importer
+            .feed(&buffer[..read])
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    let summary = importer.finish().await.map_err(|e| e.to_string())?;
+
+    for error in &summary.errors {
+        println!("Line {}: {}", error.line, error.error);
+    }
+    println!(
+        "Accepted {}, duplicates {}, rejected {}",
+        summary.accepted, summary.duplicates, summary.rejected
+    );
+
+    Ok(())
+}
+
+async fn list_rejected(db: &Pool<Postgres>, include_replayed: bool) -> Result<(), String> {
+    let messages = rejected_message::get_all(db, 100, include_replayed)
+        .await
+        .map_err(|e| e.to_string())?;
+    println!("{:<8} {:<20} {:<9} REASON", "ID", "RECEIVED", "REPLAYED");
+    for message in messages {
+        println!(
+            "{:<8} {:<20} {:<9} {}",
+            message.id,
+            message.created_at.format("%Y-%m-%d %H:%M:%S"),
+            if message.replayed_at.is_some() { "yes" } else { "no" },
+            message.reason
+        );
+    }
+
+    Ok(())
+}
+
+async fn get_rejected(db: &Pool<Postgres>, id: &str) -> Result<RejectedMessage, String> {
+    let id: i32 = id.parse().map_err(|_| format!("Invalid id {:?}", id))?;
+    rejected_message::get_by_id(db, id)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Rejected message {} not found", id))
+}
+
+// Run a quarantined message through ingestion again. Live subscribers of the
+// server do not see replayed readings, alerts are evaluated as usual.
+async fn replay_rejected(db: &Pool<Postgres>, id: &str) -> Result<(), String> {
+    let message = get_rejected(db, id).await?;
+    if message.replayed_at.is_some() {
+        return Err(format!("Rejected message {} was already replayed", message.id));
+    }
+
+    match ingest::replay_payload(
+        db,
+        &stream::channel(),
+        &message.payload,
+        message.topic.as_deref(),
+    )
+    .await
+    {
+        Ok(outcome) => {
+            rejected_message::mark_replayed(db, message.id)
+                .await
+                .map_err(|e| e.to_string())?;
+            match outcome {
+                IngestOutcome::Stored { id, .. } => println!("Stored reading {}", id),
+                IngestOutcome::Duplicate => println!("Reading was already stored"),
+            }
+            Ok(())
+        }
+        Err(e) => {
+            let reason = e.to_string();
+            rejected_message::update_reason(db, message.id, &reason)
+                .await
+                .map_err(|e| e.to_string())?;
+            Err(reason)
+        }
+    }
+}
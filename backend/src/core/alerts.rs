@@ -0,0 +1,580 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::core::notify;
+use crate::models::alert::{
+    self, AlertCondition, AlertEvent, AlertEventKind, AlertRule, AlertState,
+};
+use crate::models::data_entry::{self, DataEntry, Round};
+use crate::models::sensor::Staleness;
+
+impl AlertRule {
+    fn breached(&self, value: f64) -> bool {
+        match self.condition {
+            AlertCondition::Above => value > self.threshold,
+            AlertCondition::Below => value < self.threshold,
+            AlertCondition::Offline | AlertCondition::Ventilate => false,
+            AlertCondition::Rises | AlertCondition::Falls => value >= self.threshold,
+        }
+    }
+
+    // A firing alert only resolves once the value is back past the threshold by
+    // the hysteresis, so values hovering around the threshold do not flap
+    fn cleared(&self, value: f64) -> bool {
+        match self.condition {
+            AlertCondition::Above => value <= self.threshold - self.hysteresis,
+            AlertCondition::Below => value >= self.threshold + self.hysteresis,
+            AlertCondition::Offline | AlertCondition::Ventilate => true,
+            AlertCondition::Rises | AlertCondition::Falls => {
+                value < self.threshold - self.hysteresis
+            }
+        }
+    }
+
+    fn is_rate(&self) -> bool {
+        matches!(
+            self.condition,
+            AlertCondition::Rises | AlertCondition::Falls
+        )
+    }
+
+    // How far a value moved from the lowest or highest value within the window
+    // before it. `history` holds the values of the rule's sensor and metric.
+    fn change(&self, time: DateTime<Utc>, value: f64, history: &[(DateTime<Utc>, f64)]) -> f64 {
+        let window_start = time - Duration::seconds(self.window_seconds.into());
+        let window = history
+            .iter()
+            .filter(|(at, _)| *at >= window_start && *at <= time)
+            .map(|(_, value)| *value);
+        match self.condition {
+            AlertCondition::Falls => window.fold(value, f64::max) - value,
+            _ => value - window.fold(value, f64::min),
+        }
+    }
+
+    // Whether the rule fired too recently to fire again
+    pub fn cooling_down(&self, time: DateTime<Utc>) -> bool {
+        self.last_fired_at.is_some_and(|fired_at| {
+            time - fired_at < Duration::seconds(self.cooldown_seconds.into())
+        })
+    }
+
+    // How long the sensor of an offline rule may stay silent
+    pub fn offline_after(&self) -> Duration {
+        match self.duration_seconds {
+            0 => Staleness::from_env().offline_after,
+            seconds => Duration::seconds(seconds.into()),
+        }
+    }
+
+    // Advance an offline rule by a reading, resolving it if it fired
+    fn resume(&mut self, time: DateTime<Utc>) -> Option<AlertEventKind> {
+        self.evaluated_at = Some(time);
+        if self.state != AlertState::Firing {
+            return None;
+        }
+        self.state = AlertState::Ok;
+        self.state_since = None;
+        Some(AlertEventKind::Resolved)
+    }
+
+    // Advance the rule by one reading, returning the event it causes
+    fn step(&mut self, time: DateTime<Utc>, value: f64) -> Option<AlertEventKind> {
+        self.last_value = Some(value);
+        self.evaluated_at = Some(time);
+
+        match self.state {
+            AlertState::Ok | AlertState::Pending if !self.breached(value) => {
+                self.state = AlertState::Ok;
+                self.state_since = None;
+                None
+            }
+            AlertState::Ok | AlertState::Pending => {
+                let since = match self.state {
+                    AlertState::Pending => self.state_since.unwrap_or(time),
+                    _ => time,
+                };
+                // During the cooldown the rule waits as pending, and fires once it is over
+                if time - since >= Duration::seconds(self.duration_seconds.into())
+                    && !self.cooling_down(time)
+                {
+                    self.state = AlertState::Firing;
+                    self.state_since = Some(time);
+                    self.last_fired_at = Some(time);
+                    Some(AlertEventKind::Fired)
+                } else {
+                    self.state = AlertState::Pending;
+                    self.state_since = Some(since);
+                    None
+                }
+            }
+            AlertState::Firing if self.cleared(value) => {
+                self.state = AlertState::Ok;
+                self.state_since = None;
+                Some(AlertEventKind::Resolved)
+            }
+            AlertState::Firing => None,
+        }
+    }
+
+    fn message(&self, kind: AlertEventKind, value: f64, unit: Option<&str>) -> String {
+        let unit = unit.unwrap_or("");
+        if self.is_rate() {
+            let direction = match self.condition {
+                AlertCondition::Falls => "fell",
+                _ => "rose",
+            };
+            return match kind {
+                AlertEventKind::Fired => format!(
+                    "{}: {} of {} {} by {}{} within {} minutes",
+                    self.name,
+                    self.metric,
+                    self.unique_identifier,
+                    direction,
+                    value.to_2_decimal(),
+                    unit,
+                    f64::from(self.window_seconds) / 60.0
+                ),
+                AlertEventKind::Resolved => format!(
+                    "{}: {} of {} is steady again",
+                    self.name, self.metric, self.unique_identifier
+                ),
+            };
+        }
+        match kind {
+            AlertEventKind::Fired => {
+                let condition = match self.condition {
+                    AlertCondition::Above => "above",
+                    AlertCondition::Below => "below",
+                    _ => "",
+                };
+                format!(
+                    "{}: {} of {} is {}{}, {} {}{}",
+                    self.name,
+                    self.metric,
+                    self.unique_identifier,
+                    value,
+                    unit,
+                    condition,
+                    self.threshold,
+                    unit
+                )
+            }
+            AlertEventKind::Resolved => format!(
+                "{}: {} of {} is back to {}{}",
+                self.name, self.metric, self.unique_identifier, value, unit
+            ),
+        }
+    }
+}
+
+// Values over time, by sensor and metric
+type RateHistory = HashMap<(String, String), Vec<(DateTime<Utc>, f64)>>;
+
+// Get the stored values rate-of-change rules compare the new readings to, by
+// sensor and metric. The new readings are stored already, so they are included.
+async fn get_rate_history(
+    db: &Pool<Postgres>,
+    rules: &[AlertRule],
+    entries: &[&DataEntry],
+) -> Result<RateHistory, sqlx::Error> {
+    let rules: Vec<&AlertRule> = rules.iter().filter(|rule| rule.is_rate()).collect();
+    let (Some(first), Some(last)) = (entries.first(), entries.last()) else {
+        return Ok(HashMap::new());
+    };
+    let Some(window) = rules.iter().map(|rule| rule.window_seconds).max() else {
+        return Ok(HashMap::new());
+    };
+
+    let identifiers: Vec<String> = rules
+        .iter()
+        .map(|rule| rule.unique_identifier.clone())
+        .collect();
+    let metric_names: Vec<String> = rules.iter().map(|rule| rule.metric.clone()).collect();
+    let values = data_entry::get_metric_values(
+        db,
+        &identifiers,
+        &metric_names,
+        first.created_at - Duration::seconds(window.into()),
+        last.created_at,
+    )
+    .await?;
+
+    let mut history: RateHistory = HashMap::new();
+    for value in values {
+        history
+            .entry((value.unique_identifier, value.name))
+            .or_default()
+            .push((value.time, value.value));
+    }
+
+    Ok(history)
+}
+
+// Evaluate the alert rules on the sensors of newly stored readings, returning
+// the alerts that fired or resolved. Readings older than the last one a rule
+// has seen are skipped, so late or replayed readings cannot rewind its state.
+pub async fn evaluate(
+    db: &Pool<Postgres>,
+    entries: &[Arc<DataEntry>],
+) -> Result<Vec<AlertEvent>, sqlx::Error> {
+    let mut identifiers: Vec<String> = entries
+        .iter()
+        .map(|entry| entry.unique_identifier.clone())
+        .collect();
+    identifiers.sort();
+    identifiers.dedup();
+
+    let mut tx = db.begin().await?;
+    let rules = alert::lock_enabled_for_identifiers(&mut tx, &identifiers).await?;
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries: Vec<&DataEntry> = entries.iter().map(Arc::as_ref).collect();
+    entries.sort_by_key(|entry| entry.created_at);
+    let history = get_rate_history(db, &rules, &entries).await?;
+
+    let mut events = Vec::new();
+    for mut rule in rules {
+        // Ventilation rules are checked against the weather by their own job
+        if rule.condition == AlertCondition::Ventilate {
+            continue;
+        }
+        let evaluated_at = rule.evaluated_at;
+        let mut changed = false;
+
+        for entry in &entries {
+            if entry.unique_identifier != rule.unique_identifier
+                || evaluated_at.is_some_and(|evaluated_at| entry.created_at <= evaluated_at)
+            {
+                continue;
+            }
+            // Any reading brings an offline sensor back
+            if rule.condition == AlertCondition::Offline {
+                changed = true;
+                if let Some(kind) = rule.resume(entry.created_at) {
+                    let message =
+                        format!("{}: {} reports again", rule.name, rule.unique_identifier);
+                    events.push(
+                        alert::create_event(&mut tx, &rule, kind, None, &message, entry.created_at)
+                            .await?,
+                    );
+                }
+                continue;
+            }
+            let Some(metric) = entry
+                .metrics
+                .iter()
+                .find(|metric| metric.name == rule.metric)
+            else {
+                continue;
+            };
+
+            let value = match rule.is_rate() {
+                true => rule.change(
+                    entry.created_at,
+                    metric.value,
+                    history
+                        .get(&(rule.unique_identifier.clone(), rule.metric.clone()))
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                ),
+                false => metric.value,
+            };
+
+            changed = true;
+            if let Some(kind) = rule.step(entry.created_at, value) {
+                let message = rule.message(kind, value, metric.unit.as_deref());
+                events.push(
+                    alert::create_event(
+                        &mut tx,
+                        &rule,
+                        kind,
+                        Some(value),
+                        &message,
+                        entry.created_at,
+                    )
+                    .await?,
+                );
+            }
+        }
+
+        if changed {
+            alert::save_state(&mut tx, &rule).await?;
+        }
+    }
+
+    notify::enqueue_events(&mut tx, &events).await?;
+    tx.commit().await?;
+    if !events.is_empty() {
+        notify::wake();
+    }
+
+    Ok(events)
+}
+
+// Evaluate the threshold rules on a metric derived from readings, like the mold
+// index, which is computed after the readings are stored
+pub async fn evaluate_values(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    metric: &str,
+    values: &[(DateTime<Utc>, f64)],
+) -> Result<Vec<AlertEvent>, sqlx::Error> {
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut tx = db.begin().await?;
+    let rules =
+        alert::lock_enabled_for_identifiers(&mut tx, &[unique_identifier.to_string()]).await?;
+
+    let mut events = Vec::new();
+    for mut rule in rules {
+        if rule.metric != metric
+            || !matches!(
+                rule.condition,
+                AlertCondition::Above | AlertCondition::Below
+            )
+        {
+            continue;
+        }
+
+        let evaluated_at = rule.evaluated_at;
+        let mut changed = false;
+        for (time, value) in values {
+            if evaluated_at.is_some_and(|evaluated_at| *time <= evaluated_at) {
+                continue;
+            }
+            let value = value.to_2_decimal();
+
+            changed = true;
+            if let Some(kind) = rule.step(*time, value) {
+                let message = rule.message(kind, value, None);
+                events.push(
+                    alert::create_event(&mut tx, &rule, kind, Some(value), &message, *time).await?,
+                );
+            }
+        }
+
+        if changed {
+            alert::save_state(&mut tx, &rule).await?;
+        }
+    }
+
+    notify::enqueue_events(&mut tx, &events).await?;
+    tx.commit().await?;
+    if !events.is_empty() {
+        notify::wake();
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AlertEventKind::{Fired, Resolved};
+    use AlertState::{Firing, Ok, Pending};
+
+    fn start() -> DateTime<Utc> {
+        DateTime::from_timestamp(1700000000, 0).unwrap()
+    }
+
+    fn rule(condition: AlertCondition, threshold: f64) -> AlertRule {
+        AlertRule {
+            id: 1,
+            user_id: 1,
+            unique_identifier: "AA".to_string(),
+            name: "Test".to_string(),
+            metric: "humidity".to_string(),
+            condition,
+            threshold,
+            hysteresis: 2.0,
+            duration_seconds: 0,
+            window_seconds: 600,
+            cooldown_seconds: 0,
+            enabled: true,
+            state: AlertState::Ok,
+            state_since: None,
+            last_value: None,
+            evaluated_at: None,
+            last_fired_at: None,
+            created_at: start(),
+        }
+    }
+
+    // Feed the rule readings given as seconds after the start and value, checking
+    // the state and event after each
+    fn run(mut rule: AlertRule, steps: &[(i64, f64, AlertState, Option<AlertEventKind>)]) {
+        for (index, &(seconds, value, state, event)) in steps.iter().enumerate() {
+            let time = start() + Duration::seconds(seconds);
+            assert_eq!(rule.step(time, value), event, "event of step {}", index);
+            assert_eq!(rule.state, state, "state of step {}", index);
+            assert_eq!(rule.last_value, Some(value));
+            assert_eq!(rule.evaluated_at, Some(time));
+        }
+    }
+
+    #[test]
+    fn fires_immediately_without_duration() {
+        run(
+            rule(AlertCondition::Above, 70.0),
+            &[
+                (0, 70.0, Ok, None),
+                (10, 71.0, Firing, Some(Fired)),
+                (20, 80.0, Firing, None),
+                (30, 60.0, Ok, Some(Resolved)),
+            ],
+        );
+    }
+
+    #[test]
+    fn waits_as_pending_for_the_duration() {
+        let mut above = rule(AlertCondition::Above, 70.0);
+        above.duration_seconds = 60;
+        run(
+            above,
+            &[
+                (0, 75.0, Pending, None),
+                (30, 76.0, Pending, None),
+                // Dropping below the threshold restarts the duration
+                (40, 70.0, Ok, None),
+                (50, 75.0, Pending, None),
+                (109, 75.0, Pending, None),
+                (110, 75.0, Firing, Some(Fired)),
+            ],
+        );
+    }
+
+    #[test]
+    fn holds_within_the_hysteresis() {
+        run(
+            rule(AlertCondition::Above, 70.0),
+            &[
+                (0, 75.0, Firing, Some(Fired)),
+                (10, 69.0, Firing, None),
+                (20, 68.1, Firing, None),
+                (30, 68.0, Ok, Some(Resolved)),
+            ],
+        );
+        run(
+            rule(AlertCondition::Below, 30.0),
+            &[
+                (0, 25.0, Firing, Some(Fired)),
+                (10, 31.9, Firing, None),
+                (20, 32.0, Ok, Some(Resolved)),
+                (30, 30.0, Ok, None),
+            ],
+        );
+    }
+
+    #[test]
+    fn waits_for_the_cooldown_before_firing_again() {
+        let mut above = rule(AlertCondition::Above, 70.0);
+        above.cooldown_seconds = 300;
+        run(
+            above,
+            &[
+                (0, 75.0, Firing, Some(Fired)),
+                (60, 60.0, Ok, Some(Resolved)),
+                (120, 75.0, Pending, None),
+                (299, 75.0, Pending, None),
+                (300, 75.0, Firing, Some(Fired)),
+            ],
+        );
+    }
+
+    #[test]
+    fn cooling_down_counts_from_the_last_firing() {
+        let mut above = rule(AlertCondition::Above, 70.0);
+        assert!(!above.cooling_down(start()));
+        above.cooldown_seconds = 300;
+        above.last_fired_at = Some(start());
+        assert!(above.cooling_down(start() + Duration::seconds(299)));
+        assert!(!above.cooling_down(start() + Duration::seconds(300)));
+    }
+
+    #[test]
+    fn cleared_past_the_hysteresis() {
+        let cases = [
+            (AlertCondition::Above, 68.0, true),
+            (AlertCondition::Above, 68.5, false),
+            (AlertCondition::Above, 75.0, false),
+            (AlertCondition::Below, 72.0, true),
+            (AlertCondition::Below, 71.5, false),
+            (AlertCondition::Below, 65.0, false),
+            (AlertCondition::Rises, 67.9, true),
+            (AlertCondition::Rises, 68.0, false),
+            (AlertCondition::Falls, 67.9, true),
+            (AlertCondition::Falls, 68.0, false),
+            (AlertCondition::Offline, 100.0, true),
+            (AlertCondition::Ventilate, 100.0, true),
+        ];
+        for (condition, value, cleared) in cases {
+            assert_eq!(
+                rule(condition, 70.0).cleared(value),
+                cleared,
+                "{:?} at {}",
+                condition,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn change_without_history_is_zero() {
+        for condition in [AlertCondition::Rises, AlertCondition::Falls] {
+            assert_eq!(rule(condition, 5.0).change(start(), 66.0, &[]), 0.0);
+        }
+    }
+
+    #[test]
+    fn change_from_a_single_point() {
+        let history = [(start() - Duration::seconds(10), 60.0)];
+        assert_eq!(
+            rule(AlertCondition::Rises, 5.0).change(start(), 66.0, &history),
+            6.0
+        );
+        assert_eq!(
+            rule(AlertCondition::Falls, 5.0).change(start(), 66.0, &history),
+            0.0
+        );
+        assert_eq!(
+            rule(AlertCondition::Falls, 5.0).change(start(), 54.0, &history),
+            6.0
+        );
+    }
+
+    #[test]
+    fn change_from_the_extreme_within_the_window() {
+        let history = [
+            (start() - Duration::seconds(500), 58.0),
+            (start() - Duration::seconds(300), 64.0),
+            (start() - Duration::seconds(100), 61.0),
+        ];
+        assert_eq!(
+            rule(AlertCondition::Rises, 5.0).change(start(), 62.0, &history),
+            4.0
+        );
+        assert_eq!(
+            rule(AlertCondition::Falls, 5.0).change(start(), 62.0, &history),
+            2.0
+        );
+    }
+
+    #[test]
+    fn change_includes_a_reading_at_the_window_start() {
+        let rises = rule(AlertCondition::Rises, 5.0);
+        let at_start = [(start() - Duration::seconds(600), 50.0)];
+        assert_eq!(rises.change(start(), 60.0, &at_start), 10.0);
+        let before_start = [(start() - Duration::seconds(601), 50.0)];
+        assert_eq!(rises.change(start(), 60.0, &before_start), 0.0);
+        // Readings newer than the one evaluated are not part of its window
+        let later = [(start() + Duration::seconds(1), 50.0)];
+        assert_eq!(rises.change(start(), 60.0, &later), 0.0);
+    }
+}
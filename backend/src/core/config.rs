@@ -0,0 +1,54 @@
+use log::warn;
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+
+// Read a setting from the environment, falling back to a default when unset or invalid
+pub fn env_or<T: FromStr + Display>(key: &str, default: T) -> T {
+    let Ok(value) = env::var(key) else {
+        return default;
+    };
+    match value.parse() {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            // A typo should not silently leave the setting at its default
+            warn!(
+                "Ignoring invalid {}={:?}, using {} instead",
+                key, value, default
+            );
+            default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test sets its own variables, as tests run in parallel
+    fn set(key: &str, value: &str) {
+        unsafe { env::set_var(key, value) };
+    }
+
+    #[test]
+    fn parses_set_values() {
+        set("CONFIG_TEST_NUMBER", "42");
+        set("CONFIG_TEST_FLAG", "false");
+        set("CONFIG_TEST_FLOAT", "2.5");
+        assert_eq!(env_or("CONFIG_TEST_NUMBER", 7), 42);
+        assert!(!env_or("CONFIG_TEST_FLAG", true));
+        assert_eq!(env_or("CONFIG_TEST_FLOAT", 1.0), 2.5);
+    }
+
+    #[test]
+    fn falls_back_to_the_default() {
+        set("CONFIG_TEST_INVALID", "ten");
+        set("CONFIG_TEST_NEGATIVE", "-1");
+        set("CONFIG_TEST_EMPTY", "");
+        assert_eq!(env_or("CONFIG_TEST_UNSET", 7), 7);
+        assert_eq!(env_or("CONFIG_TEST_INVALID", 7), 7);
+        assert_eq!(env_or::<u64>("CONFIG_TEST_NEGATIVE", 7), 7);
+        assert_eq!(env_or("CONFIG_TEST_EMPTY", 7), 7);
+        assert!(env_or("CONFIG_TEST_INVALID", true));
+    }
+}
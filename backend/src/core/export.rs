@@ -0,0 +1,216 @@
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use axum::body::{Body, Bytes};
+use futures_util::{StreamExt, stream};
+use log::warn;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use sqlx::{Pool, Postgres};
+use std::io;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::core::config::env_or;
+use crate::models::data_entry::{ExportFormat, ExportRow, stream_export_rows};
+
+// Rows gathered before a chunk is sent, and a Parquet row group is written
+const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+// Chunks buffered ahead of a slow client
+const CHANNEL_CAPACITY: usize = 4;
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+// What to export, resolved from the request
+pub struct Export {
+    pub user_id: i32,
+    pub identifiers: Option<Vec<String>>,
+    pub from: chrono::DateTime<chrono::Utc>,
+    pub to: chrono::DateTime<chrono::Utc>,
+    pub metric_names: Option<Vec<String>>,
+    pub format: ExportFormat,
+}
+
+// Turns batches of rows into chunks of the output file
+enum Encoder {
+    Csv { header_written: bool },
+    Ndjson,
+    Parquet(Box<ArrowWriter<Vec<u8>>>),
+}
+
+fn parquet_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new(
+            "time",
+            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("unique_identifier", DataType::Utf8, false),
+        Field::new("label", DataType::Utf8, false),
+        Field::new("metric", DataType::Utf8, false),
+        Field::new("value", DataType::Float64, false),
+        Field::new("unit", DataType::Utf8, true),
+    ]))
+}
+
+impl Encoder {
+    fn new(format: ExportFormat) -> Result<Self, String> {
+        Ok(match format {
+            ExportFormat::Csv => Encoder::Csv {
+                header_written: false,
+            },
+            ExportFormat::Ndjson => Encoder::Ndjson,
+            ExportFormat::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let writer = ArrowWriter::try_new(Vec::new(), parquet_schema(), Some(props))
+                    .map_err(|e| e.to_string())?;
+                Encoder::Parquet(Box::new(writer))
+            }
+        })
+    }
+
+    fn encode(&mut self, rows: &[ExportRow]) -> Result<Vec<u8>, String> {
+        match self {
+            Encoder::Csv { header_written } => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(!*header_written)
+                    .from_writer(Vec::new());
+                for row in rows {
+                    writer.serialize(row).map_err(|e| e.to_string())?;
+                }
+                *header_written = true;
+                writer.into_inner().map_err(|e| e.to_string())
+            }
+            Encoder::Ndjson => {
+                let mut buffer = Vec::new();
+                for row in rows {
+                    serde_json::to_writer(&mut buffer, row).map_err(|e| e.to_string())?;
+                    buffer.push(b'\n');
+                }
+                Ok(buffer)
+            }
+            Encoder::Parquet(writer) => {
+                let columns: Vec<ArrayRef> = vec![
+                    Arc::new(
+                        TimestampMicrosecondArray::from_iter_values(
+                            rows.iter().map(|row| row.time.timestamp_micros()),
+                        )
+                        .with_timezone("UTC"),
+                    ),
+                    Arc::new(StringArray::from_iter_values(
+                        rows.iter().map(|row| &row.unique_identifier),
+                    )),
+                    Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.label))),
+                    Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.metric))),
+                    Arc::new(Float64Array::from_iter_values(rows.iter().map(|row| row.value))),
+                    Arc::new(StringArray::from_iter(
+                        rows.iter().map(|row| row.unit.as_deref()),
+                    )),
+                ];
+                let batch = RecordBatch::try_new(parquet_schema(), columns)
+                    .map_err(|e| e.to_string())?;
+                writer.write(&batch).map_err(|e| e.to_string())?;
+                // Close the row group so its bytes can be sent right away
+                writer.flush().map_err(|e| e.to_string())?;
+                Ok(std::mem::take(writer.inner_mut()))
+            }
+        }
+    }
+
+    // Bytes that end the file, the footer for Parquet
+    fn finish(self) -> Result<Vec<u8>, String> {
+        match self {
+            Encoder::Csv { header_written } if !header_written => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                writer
+                    .write_record(["time", "unique_identifier", "label", "metric", "value", "unit"])
+                    .map_err(|e| e.to_string())?;
+                writer.into_inner().map_err(|e| e.to_string())
+            }
+            Encoder::Csv { .. } | Encoder::Ndjson => Ok(Vec::new()),
+            Encoder::Parquet(writer) => writer.into_inner().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Read the rows in batches, encode them and hand the chunks to the channel.
+// Returns early when the client goes away.
+async fn produce(
+    db: Pool<Postgres>,
+    export: Export,
+    sender: mpsc::Sender<Result<Bytes, io::Error>>,
+) -> Result<(), String> {
+    let batch_size: usize = env_or("EXPORT_BATCH_SIZE", DEFAULT_BATCH_SIZE).max(1);
+    let mut encoder = Encoder::new(export.format)?;
+    let mut rows = stream_export_rows(
+        &db,
+        export.user_id,
+        export.identifiers,
+        export.from,
+        export.to,
+        export.metric_names,
+    );
+
+    let mut batch = Vec::with_capacity(batch_size);
+    loop {
+        let row = rows.next().await.transpose().map_err(|e| e.to_string())?;
+        let done = row.is_none();
+        batch.extend(row);
+
+        if batch.len() >= batch_size || (done && !batch.is_empty()) {
+            let chunk = encoder.encode(&batch)?;
+            batch.clear();
+            if sender.send(Ok(Bytes::from(chunk))).await.is_err() {
+                return Ok(());
+            }
+        }
+        if done {
+            break;
+        }
+    }
+
+    let chunk = encoder.finish()?;
+    if !chunk.is_empty() {
+        let _ = sender.send(Ok(Bytes::from(chunk))).await;
+    }
+
+    Ok(())
+}
+
+// Body that streams the export as it is read from the database, so memory use
+// stays bounded by the batch size however large the range is
+pub fn body(db: &Pool<Postgres>, export: Export) -> Body {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let db = db.clone();
+    tokio::spawn(async move {
+        let user_id = export.user_id;
+        if let Err(e) = produce(db, export, sender.clone()).await {
+            warn!("Export for user {} failed: {}", user_id, e);
+            // Abort the response so the client does not take a truncated file as complete
+            let _ = sender.send(Err(io::Error::other(e))).await;
+        }
+    });
+
+    Body::from_stream(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    }))
+}
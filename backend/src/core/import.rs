@@ -0,0 +1,262 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::{HashMap, HashSet};
+
+use crate::core::config::env_or;
+use crate::models::data_entry::{Metric, NewDataEntry, create_many, default_unit};
+
+// Readings stored per transaction
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+// Rejected rows listed in the summary, the rest are only counted
+const MAX_LISTED_ERRORS: usize = 100;
+
+// File format of an import, the same columns `/export` writes
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ImportFormat {
+    // Guess the format from a content type or file name
+    pub fn detect(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if name.contains("csv") {
+            Some(ImportFormat::Csv)
+        } else if name.contains("json") {
+            Some(ImportFormat::Ndjson)
+        } else {
+            None
+        }
+    }
+}
+
+// One metric of one reading. Other columns, like the label of an export, are ignored.
+#[derive(Deserialize)]
+struct ImportRow {
+    time: DateTime<Utc>,
+    unique_identifier: String,
+    metric: String,
+    value: f64,
+    unit: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RowError {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportSummary {
+    // Rows are counted per metric value
+    pub accepted: usize,
+    pub duplicates: usize,
+    pub rejected: usize,
+    pub errors: Vec<RowError>,
+}
+
+// Reads a file line by line as it arrives and stores the readings in batches.
+// Rows of the same reading are merged into one entry, so they should be next to
+// each other, as they are in an export. Fields must not contain line breaks.
+pub struct Importer {
+    db: Pool<Postgres>,
+    format: ImportFormat,
+    // Identifiers rows may be imported for
+    allowed: HashSet<String>,
+    batch_size: usize,
+    header: Option<csv::StringRecord>,
+    partial: Vec<u8>,
+    line: usize,
+    pending: Vec<NewDataEntry>,
+    pending_index: HashMap<(String, DateTime<Utc>), usize>,
+    summary: ImportSummary,
+}
+
+impl Importer {
+    pub fn new(db: &Pool<Postgres>, format: ImportFormat, allowed: HashSet<String>) -> Self {
+        Self {
+            db: db.clone(),
+            format,
+            allowed,
+            batch_size: env_or("IMPORT_BATCH_SIZE", DEFAULT_BATCH_SIZE).max(1),
+            header: None,
+            partial: Vec::new(),
+            line: 0,
+            pending: Vec::new(),
+            pending_index: HashMap::new(),
+            summary: ImportSummary::default(),
+        }
+    }
+
+    // Feed the next part of the file
+    pub async fn feed(&mut self, chunk: &[u8]) -> Result<(), sqlx::Error> {
+        self.partial.extend_from_slice(chunk);
+        let Some(end) = self.partial.iter().rposition(|&b| b == b'\n') else {
+            return Ok(());
+        };
+        let complete: Vec<u8> = self.partial.drain(..=end).collect();
+
+        for line in complete[..end].split(|&b| b == b'\n') {
+            self.handle_line(line);
+        }
+
+        if self.pending.len() > self.batch_size {
+            // The last reading may continue in the next chunk, so it waits for the next batch
+            let last = self.pending.pop();
+            self.flush().await?;
+            if let Some(last) = last {
+                self.pending_index
+                    .insert((last.unique_identifier.clone(), last.created_at), 0);
+                self.pending.push(last);
+            }
+        }
+        Ok(())
+    }
+
+    // Handle the last line and store what is left
+    pub async fn finish(mut self) -> Result<ImportSummary, sqlx::Error> {
+        let rest = std::mem::take(&mut self.partial);
+        self.handle_line(&rest);
+        self.flush().await?;
+        Ok(self.summary)
+    }
+
+    fn handle_line(&mut self, line: &[u8]) {
+        self.line += 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+
+        let row = match self.parse(line) {
+            Ok(Some(row)) => row,
+            Ok(None) => return,
+            Err(e) => {
+                self.reject(e);
+                return;
+            }
+        };
+        if let Err(e) = self.add(row) {
+            self.reject(e);
+        }
+    }
+
+    // Parse a line, None for the CSV header
+    fn parse(&mut self, line: &[u8]) -> Result<Option<ImportRow>, String> {
+        match self.format {
+            ImportFormat::Ndjson => serde_json::from_slice(line)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            ImportFormat::Csv => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .from_reader(line);
+                let record = match reader.records().next() {
+                    Some(record) => record.map_err(|e| e.to_string())?,
+                    None => return Ok(None),
+                };
+                match &self.header {
+                    None => {
+                        self.header = Some(record);
+                        Ok(None)
+                    }
+                    Some(header) => record
+                        .deserialize(Some(header))
+                        .map(Some)
+                        .map_err(|e| e.to_string()),
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, row: ImportRow) -> Result<(), String> {
+        let unique_identifier = row.unique_identifier.trim().to_string();
+        if !self.allowed.contains(&unique_identifier) {
+            return Err(format!("Device {} is not yours", unique_identifier));
+        }
+        let name = row.metric.trim().to_lowercase();
+        if name.is_empty() {
+            return Err("Metric name is empty".to_string());
+        }
+        // The same limits as readings ingested from sensors
+        if name.len() > 50 {
+            return Err(format!("Invalid metric name: {:?}", name));
+        }
+        if !row.value.is_finite() {
+            return Err(format!("Invalid value for {}", name));
+        }
+        let unit = row
+            .unit
+            .filter(|unit| !unit.is_empty())
+            .or_else(|| default_unit(&name));
+        if unit.as_ref().is_some_and(|unit| unit.len() > 16) {
+            return Err(format!("Invalid unit for metric {}", name));
+        }
+
+        let key = (unique_identifier, row.time);
+        let index = match self.pending_index.get(&key) {
+            Some(&index) => index,
+            None => {
+                self.pending.push(NewDataEntry {
+                    unique_identifier: key.0.clone(),
+                    created_at: row.time,
+                    topic: None,
+                    metrics: Vec::new(),
+                });
+                self.pending_index.insert(key, self.pending.len() - 1);
+                self.pending.len() - 1
+            }
+        };
+
+        let metrics = &mut self.pending[index].metrics;
+        if metrics.iter().any(|metric| metric.name == name) {
+            return Err(format!("Metric {} appears twice in the same reading", name));
+        }
+        metrics.push(Metric {
+            name,
+            value: row.value,
+            unit,
+        });
+        Ok(())
+    }
+
+    fn reject(&mut self, error: String) {
+        self.summary.rejected += 1;
+        if self.summary.errors.len() < MAX_LISTED_ERRORS {
+            self.summary.errors.push(RowError {
+                line: self.line,
+                error,
+            });
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), sqlx::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        // The summary counts the rows of the file, not the metrics derived from them
+        let rows: Vec<usize> = self
+            .pending
+            .iter()
+            .map(|entry| entry.metrics.len())
+            .collect();
+        for entry in self.pending.iter_mut() {
+            entry.add_derived_metrics();
+        }
+        let created = create_many(&self.db, &self.pending).await?;
+        for (rows, created) in rows.into_iter().zip(created) {
+            match created {
+                Some(_) => self.summary.accepted += rows,
+                None => self.summary.duplicates += rows,
+            }
+        }
+        self.pending.clear();
+        self.pending_index.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,622 @@
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::core::alerts;
+use crate::core::config::env_or;
+use crate::core::stream::LiveReadings;
+use crate::models::data_entry::{self, DataEntry, HUMIDITY, Metric, NewDataEntry, default_unit};
+use crate::models::device;
+use crate::models::weather;
+
+// Readings rejected because they did not prove which device sent them, since startup
+static UNAUTHENTICATED_READINGS: AtomicU64 = AtomicU64::new(0);
+
+pub fn unauthenticated_readings() -> u64 {
+    UNAUTHENTICATED_READINGS.load(Ordering::Relaxed)
+}
+
+#[derive(Deserialize)]
+pub struct MQQTMessage {
+    pub mac: String,
+    pub timestamp: i64,
+    // Legacy single-metric field, still sent by older sensors
+    pub humidity: Option<f64>,
+    #[serde(default)]
+    pub metrics: Vec<Metric>,
+    // Device token, for transports that cannot carry it out of band
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+// A validated reading and the token it was sent with
+pub struct Reading {
+    pub entry: NewDataEntry,
+    pub token: Option<String>,
+}
+
+// Why a payload could not be stored
+#[derive(Debug)]
+pub enum IngestError {
+    Invalid(String),
+    // The reading did not come from the device it claims to be from
+    Unauthenticated(String),
+    Database(sqlx::Error),
+}
+
+impl IngestError {
+    // Whether trying again later could succeed, e.g. while the database is unreachable
+    pub fn is_transient(&self) -> bool {
+        match self {
+            IngestError::Invalid(_) | IngestError::Unauthenticated(_) => false,
+            IngestError::Database(e) => is_transient_database_error(e),
+        }
+    }
+}
+
+pub fn is_transient_database_error(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_)
+        | sqlx::Error::Protocol(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed => true,
+        // Connection exceptions, insufficient resources, operator intervention,
+        // serialization failures and deadlocks
+        sqlx::Error::Database(db_error) => db_error.code().is_some_and(|code| {
+            code.starts_with("08")
+                || code.starts_with("53")
+                || code.starts_with("57P")
+                || code == "40001"
+                || code == "40P01"
+        }),
+        _ => false,
+    }
+}
+
+// Result of ingesting a single reading
+#[derive(Debug)]
+pub enum IngestOutcome {
+    Stored {
+        id: i32,
+        created_at: DateTime<Utc>,
+    },
+    // Redelivery of a reading that is already stored
+    Duplicate,
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::Invalid(reason) | IngestError::Unauthenticated(reason) => {
+                write!(f, "{}", reason)
+            }
+            IngestError::Database(e) => write!(f, "Database insert failed: {}", e),
+        }
+    }
+}
+
+impl MQQTMessage {
+    // Validate the message and turn it into a reading that can be stored
+    pub fn into_reading(self, topic: Option<&str>) -> Result<Reading, String> {
+        // Virtual sensors are only written by the backend itself
+        if self.mac.is_empty() || self.mac.len() > 25 || weather::is_virtual(&self.mac) {
+            return Err(format!("Invalid identifier: {:?}", self.mac));
+        }
+
+        // Convert epoch timestamp to DateTime<Utc>
+        let created_at = DateTime::from_timestamp(self.timestamp, 0)
+            .ok_or_else(|| format!("Invalid timestamp value: {}", self.timestamp))?;
+
+        let mut metrics = self.metrics;
+        if let Some(humidity) = self.humidity {
+            metrics.push(Metric {
+                name: HUMIDITY.to_string(),
+                value: humidity,
+                unit: None,
+            });
+        }
+
+        if metrics.is_empty() {
+            return Err("Message contains no metrics".to_string());
+        }
+
+        let mut names = HashSet::new();
+        for metric in metrics.iter_mut() {
+            metric.name = metric.name.trim().to_lowercase();
+            if metric.name.is_empty() || metric.name.len() > 50 {
+                return Err(format!("Invalid metric name: {:?}", metric.name));
+            }
+            if !metric.value.is_finite() {
+                return Err(format!("Invalid value for metric {}", metric.name));
+            }
+            if !names.insert(metric.name.clone()) {
+                return Err(format!("Duplicate metric: {}", metric.name));
+            }
+            match &metric.unit {
+                Some(unit) if unit.len() > 16 => {
+                    return Err(format!("Invalid unit for metric {}", metric.name));
+                }
+                Some(_) => {}
+                None => metric.unit = default_unit(&metric.name),
+            }
+        }
+
+        Ok(Reading {
+            entry: NewDataEntry {
+                unique_identifier: self.mac,
+                created_at,
+                topic: topic.map(|topic| topic.to_string()),
+                metrics,
+            },
+            token: self.token,
+        })
+    }
+}
+
+// How a single item of an ingest request was handled
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestStatus {
+    Stored,
+    Duplicate,
+    Rejected,
+}
+
+// Per-item result of an ingest request, in the order the items were sent
+#[derive(Serialize, Debug)]
+pub struct IngestItemResult {
+    pub index: usize,
+    pub status: IngestStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl IngestItemResult {
+    fn rejected(index: usize, error: String) -> Self {
+        Self {
+            index,
+            status: IngestStatus::Rejected,
+            id: None,
+            error: Some(error),
+        }
+    }
+}
+
+// Drop the device token from a raw payload before it is kept anywhere, so a
+// quarantined reading cannot be used to forge others. Payloads that are not a
+// JSON object are returned as they are.
+pub fn without_token(payload: &[u8]) -> Vec<u8> {
+    match serde_json::from_slice::<serde_json::Value>(payload) {
+        Ok(serde_json::Value::Object(mut message)) if message.contains_key("token") => {
+            message.remove("token");
+            serde_json::to_vec(&message).unwrap_or_default()
+        }
+        _ => payload.to_vec(),
+    }
+}
+
+// Whether readings of unregistered devices are rejected. Only meant to be turned
+// off while the sensors of an existing installation are being registered.
+pub fn device_auth_required() -> bool {
+    env_or("DEVICE_AUTH_REQUIRED", true)
+}
+
+// Parse a raw payload into a validated reading
+pub fn parse_payload(payload: &str, topic: Option<&str>) -> Result<Reading, IngestError> {
+    let message: MQQTMessage = serde_json::from_str(payload)
+        .map_err(|e| IngestError::Invalid(format!("Invalid message format: {}", e)))?;
+
+    message.into_reading(topic).map_err(IngestError::Invalid)
+}
+
+// Check that each reading carries the token of the device it claims to be from.
+// `token` is used for readings that do not carry their own, e.g. from an HTTP header.
+// Unregistered devices are only accepted without a token, while
+// DEVICE_AUTH_REQUIRED is disabled.
+pub async fn authenticate(
+    db: &Pool<Postgres>,
+    readings: Vec<Reading>,
+    token: Option<&str>,
+) -> Result<Vec<Result<NewDataEntry, String>>, sqlx::Error> {
+    if readings.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let auth_required = device_auth_required();
+    let identifiers: Vec<String> = readings
+        .iter()
+        .map(|reading| reading.entry.unique_identifier.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let token_hashes: HashMap<String, String> =
+        device::get_token_hashes(db, &identifiers).await?;
+
+    let mut authenticated = HashSet::new();
+    let mut unregistered = HashSet::new();
+    let mut rejected = Vec::new();
+    let mut results = Vec::with_capacity(readings.len());
+
+    for reading in readings {
+        let identifier = &reading.entry.unique_identifier;
+        let token = reading.token.as_deref().or(token);
+
+        let result = match (token_hashes.get(identifier), token) {
+            (Some(token_hash), Some(token)) if device::hash_token(token) == *token_hash => Ok(()),
+            (Some(_), Some(_)) => Err(format!("Invalid token for device {}", identifier)),
+            (Some(_), None) => Err(format!("Missing token for device {}", identifier)),
+            // A token is never accepted for a device that is not registered
+            (None, None) if !auth_required => Ok(()),
+            (None, _) => Err(format!("Unknown device {}", identifier)),
+        };
+
+        match result {
+            Ok(()) => {
+                match token_hashes.contains_key(identifier) {
+                    true => authenticated.insert(identifier.clone()),
+                    false => unregistered.insert(identifier.clone()),
+                };
+                results.push(Ok(reading.entry));
+            }
+            Err(reason) => {
+                UNAUTHENTICATED_READINGS.fetch_add(1, Ordering::Relaxed);
+                rejected.push(identifier.clone());
+                results.push(Err(reason));
+            }
+        }
+    }
+
+    if !unregistered.is_empty() {
+        warn!(
+            "Accepted readings of unregistered devices {:?} as DEVICE_AUTH_REQUIRED is disabled",
+            unregistered
+        );
+    }
+    if !rejected.is_empty() {
+        device::record_rejections(db, &rejected).await?;
+    }
+    if !authenticated.is_empty() {
+        let authenticated: Vec<String> = authenticated.into_iter().collect();
+        device::touch(db, &authenticated).await?;
+    }
+
+    Ok(results)
+}
+
+// Store already validated readings in one batch and hand the new ones to live subscribers
+pub async fn store_entries(
+    db: &Pool<Postgres>,
+    live: &LiveReadings,
+    mut entries: Vec<NewDataEntry>,
+) -> Result<Vec<IngestOutcome>, sqlx::Error> {
+    for entry in entries.iter_mut() {
+        entry.add_derived_metrics();
+    }
+    let created = data_entry::create_many(db, &entries).await?;
+
+    let mut stored = Vec::new();
+    let outcomes = entries
+        .into_iter()
+        .zip(created)
+        .map(|(entry, created)| match created {
+            Some((id, created_at)) => {
+                stored.push(Arc::new(DataEntry {
+                    id,
+                    unique_identifier: entry.unique_identifier,
+                    created_at,
+                    label: None,
+                    topic: entry.topic,
+                    metrics: entry.metrics,
+                }));
+                IngestOutcome::Stored { id, created_at }
+            }
+            None => IngestOutcome::Duplicate,
+        })
+        .collect();
+
+    // The readings are stored either way, so a failed evaluation is only logged
+    match alerts::evaluate(db, &stored).await {
+        Ok(events) => {
+            for event in &events {
+                info!("Alert {:?}: {}", event.kind, event.message);
+            }
+        }
+        Err(e) => error!("Failed to evaluate alert rules: {}", e),
+    }
+
+    for entry in stored {
+        // Sending only fails when nobody is subscribed
+        let _ = live.send(entry);
+    }
+
+    Ok(outcomes)
+}
+
+// Parse, validate and store a raw payload
+pub async fn store_payload(
+    db: &Pool<Postgres>,
+    live: &LiveReadings,
+    payload: &str,
+    topic: Option<&str>,
+) -> Result<IngestOutcome, IngestError> {
+    let reading = parse_payload(payload, topic)?;
+    let entry = match authenticate(db, vec![reading], None).await {
+        Ok(mut results) => results.remove(0).map_err(IngestError::Unauthenticated)?,
+        Err(e) => return Err(IngestError::Database(e)),
+    };
+
+    match store_entries(db, live, vec![entry]).await {
+        Ok(mut outcomes) => Ok(outcomes.remove(0)),
+        Err(e) => Err(IngestError::Database(e)),
+    }
+}
+
+// Parse and store a quarantined payload again. Payloads are quarantined without
+// their token, so the reading is trusted as long as its device is registered.
+pub async fn replay_payload(
+    db: &Pool<Postgres>,
+    live: &LiveReadings,
+    payload: &str,
+    topic: Option<&str>,
+) -> Result<IngestOutcome, IngestError> {
+    let reading = parse_payload(payload, topic)?;
+    let identifier = reading.entry.unique_identifier.clone();
+    let registered = device::get_token_hashes(db, std::slice::from_ref(&identifier))
+        .await
+        .map_err(IngestError::Database)?;
+    if registered.is_empty() && device_auth_required() {
+        return Err(IngestError::Unauthenticated(format!(
+            "Unknown device {}",
+            identifier
+        )));
+    }
+
+    match store_entries(db, live, vec![reading.entry]).await {
+        Ok(mut outcomes) => Ok(outcomes.remove(0)),
+        Err(e) => Err(IngestError::Database(e)),
+    }
+}
+
+// Validate, authenticate and store already decoded messages, e.g. the items of
+// an HTTP batch. Rejected items are reported without affecting the rest of the batch.
+pub async fn store_values(
+    db: &Pool<Postgres>,
+    live: &LiveReadings,
+    values: Vec<serde_json::Value>,
+    topic: Option<&str>,
+    token: Option<&str>,
+) -> Result<Vec<IngestItemResult>, sqlx::Error> {
+    let mut results = Vec::with_capacity(values.len());
+    let mut readings = Vec::new();
+    let mut reading_indexes = Vec::new();
+
+    for (index, value) in values.into_iter().enumerate() {
+        let reading = serde_json::from_value::<MQQTMessage>(value)
+            .map_err(|e| format!("Invalid message format: {}", e))
+            .and_then(|message| message.into_reading(topic));
+
+        match reading {
+            Ok(reading) => {
+                readings.push(reading);
+                reading_indexes.push(index);
+            }
+            Err(error) => results.push(IngestItemResult::rejected(index, error)),
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut entry_indexes = Vec::new();
+    for (index, result) in reading_indexes
+        .into_iter()
+        .zip(authenticate(db, readings, token).await?)
+    {
+        match result {
+            Ok(entry) => {
+                entries.push(entry);
+                entry_indexes.push(index);
+            }
+            Err(error) => results.push(IngestItemResult::rejected(index, error)),
+        }
+    }
+
+    if !entries.is_empty() {
+        let outcomes = store_entries(db, live, entries).await?;
+        for (index, outcome) in entry_indexes.into_iter().zip(outcomes) {
+            results.push(match outcome {
+                IngestOutcome::Stored { id, .. } => IngestItemResult {
+                    index,
+                    status: IngestStatus::Stored,
+                    id: Some(id),
+                    error: None,
+                },
+                IngestOutcome::Duplicate => IngestItemResult {
+                    index,
+                    status: IngestStatus::Duplicate,
+                    id: None,
+                    error: None,
+                },
+            });
+        }
+    }
+
+    results.sort_by_key(|result| result.index);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The reason a payload is rejected with
+    fn rejection(payload: &str) -> String {
+        match parse_payload(payload, None) {
+            Err(IngestError::Invalid(reason)) => reason,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("payload was accepted: {}", payload),
+        }
+    }
+
+    fn message(mac: &str, metrics: Vec<Metric>) -> MQQTMessage {
+        MQQTMessage {
+            mac: mac.to_string(),
+            timestamp: 1700000000,
+            humidity: None,
+            metrics,
+            token: None,
+        }
+    }
+
+    fn metric(name: &str, value: f64) -> Metric {
+        Metric {
+            name: name.to_string(),
+            value,
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn parses_legacy_humidity_and_metrics() {
+        let reading = parse_payload(
+            r#"{"mac":"AA","timestamp":1700000000,"humidity":41.5,"token":"t",
+                "metrics":[{"name":" Temperature ","value":21.0},{"name":"co2","value":600,"unit":"ppm"}]}"#,
+            Some("sensors/aa"),
+        )
+        .unwrap();
+
+        assert_eq!(reading.token.as_deref(), Some("t"));
+        assert_eq!(reading.entry.unique_identifier, "AA");
+        assert_eq!(reading.entry.created_at.timestamp(), 1700000000);
+        assert_eq!(reading.entry.topic.as_deref(), Some("sensors/aa"));
+        let metrics: Vec<_> = reading
+            .entry
+            .metrics
+            .iter()
+            .map(|metric| (metric.name.as_str(), metric.value, metric.unit.as_deref()))
+            .collect();
+        assert_eq!(
+            metrics,
+            vec![
+                ("temperature", 21.0, Some("°C")),
+                ("co2", 600.0, Some("ppm")),
+                ("humidity", 41.5, Some("%")),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        assert!(rejection("not json").starts_with("Invalid message format"));
+        assert!(
+            rejection(r#"{"timestamp":1700000000,"humidity":40}"#)
+                .starts_with("Invalid message format")
+        );
+        assert!(rejection(r#"{"mac":"AA","humidity":40}"#).starts_with("Invalid message format"));
+        assert!(
+            rejection(r#"{"mac":"AA","timestamp":"now","humidity":40}"#)
+                .starts_with("Invalid message format")
+        );
+        assert!(
+            rejection(r#"{"mac":"AA","timestamp":1700000000,"humidity":"40"}"#)
+                .starts_with("Invalid message format")
+        );
+    }
+
+    #[test]
+    fn rejects_missing_values() {
+        assert_eq!(
+            rejection(r#"{"mac":"AA","timestamp":1700000000}"#),
+            "Message contains no metrics"
+        );
+        assert_eq!(
+            rejection(r#"{"mac":"AA","timestamp":1700000000,"humidity":null,"metrics":[]}"#),
+            "Message contains no metrics"
+        );
+        assert!(
+            rejection(r#"{"mac":"AA","timestamp":1700000000,"metrics":[{"name":"co2"}]}"#)
+                .starts_with("Invalid message format")
+        );
+    }
+
+    #[test]
+    fn rejects_non_finite_values() {
+        // JSON cannot carry NaN, but a decoded message can
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let result = message("AA", vec![metric("co2", value)]).into_reading(None);
+            assert_eq!(result.err().unwrap(), "Invalid value for metric co2");
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_numbers() {
+        assert!(
+            rejection(r#"{"mac":"AA","timestamp":1700000000,"humidity":1e400}"#)
+                .starts_with("Invalid message format")
+        );
+        assert!(
+            rejection(r#"{"mac":"AA","timestamp":99999999999999999999,"humidity":40}"#)
+                .starts_with("Invalid message format")
+        );
+        assert_eq!(
+            rejection(r#"{"mac":"AA","timestamp":9223372036854775807,"humidity":40}"#),
+            "Invalid timestamp value: 9223372036854775807"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_identifiers() {
+        assert_eq!(
+            rejection(r#"{"mac":"","timestamp":1700000000,"humidity":40}"#),
+            r#"Invalid identifier: """#
+        );
+        let long = "A".repeat(26);
+        assert_eq!(
+            rejection(&format!(
+                r#"{{"mac":"{}","timestamp":1700000000,"humidity":40}}"#,
+                long
+            )),
+            format!("Invalid identifier: {:?}", long)
+        );
+        assert!(
+            message(&"A".repeat(25), vec![metric("co2", 1.0)])
+                .into_reading(None)
+                .is_ok()
+        );
+        assert_eq!(
+            rejection(r#"{"mac":"weather:home","timestamp":1700000000,"humidity":40}"#),
+            r#"Invalid identifier: "weather:home""#
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_metrics() {
+        let result = message("AA", vec![metric("  ", 1.0)]).into_reading(None);
+        assert_eq!(result.err().unwrap(), r#"Invalid metric name: """#);
+        let result = message("AA", vec![metric(&"x".repeat(51), 1.0)]).into_reading(None);
+        assert!(result.err().unwrap().starts_with("Invalid metric name"));
+
+        // Names are compared after normalisation, and the legacy field counts too
+        let result =
+            message("AA", vec![metric("CO2", 1.0), metric("co2 ", 2.0)]).into_reading(None);
+        assert_eq!(result.err().unwrap(), "Duplicate metric: co2");
+        assert_eq!(
+            rejection(
+                r#"{"mac":"AA","timestamp":1700000000,"humidity":40,"metrics":[{"name":"humidity","value":41}]}"#
+            ),
+            "Duplicate metric: humidity"
+        );
+
+        let mut long_unit = metric("co2", 1.0);
+        long_unit.unit = Some("u".repeat(17));
+        let result = message("AA", vec![long_unit]).into_reading(None);
+        assert_eq!(result.err().unwrap(), "Invalid unit for metric co2");
+    }
+}
@@ -1,3 +1,17 @@
+pub mod alerts;
+pub mod config;
 pub mod database;
+pub mod export;
+pub mod import;
+pub mod ingest;
 pub mod logger;
 pub mod message_queue;
+pub mod mold;
+pub mod notify;
+pub mod psychrometrics;
+pub mod rollup;
+pub mod smtp;
+pub mod stream;
+pub mod ventilation;
+pub mod watchdog;
+pub mod weather;
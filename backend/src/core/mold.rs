@@ -0,0 +1,225 @@
+use chrono::{Duration, DurationRound, Utc};
+use log::{error, info};
+use sqlx::{Pool, Postgres};
+
+use crate::core::alerts;
+use crate::core::config::env_or;
+use crate::models::alert::AlertEvent;
+use crate::models::mold::{self, MOLD_INDEX, MoldPoint, MoldState};
+use crate::models::rollup;
+
+// Humidity below which mold cannot grow at a temperature, in percent. Lower in
+// the warmth, levelling off at 80% above 20°C.
+fn critical_humidity(temperature: f64) -> f64 {
+    if temperature > 20.0 {
+        return 80.0;
+    }
+    -0.00267 * temperature.powi(3) + 0.160 * temperature.powi(2) - 3.13 * temperature + 100.0
+}
+
+impl MoldState {
+    // Advance the VTT model (Hukka & Viitanen, 1999) by an hour, for a
+    // sensitive material like pine sapwood. Returns whether the hour favoured
+    // growth.
+    pub fn step(&mut self, temperature: f64, humidity: f64) -> bool {
+        let critical = critical_humidity(temperature);
+        let favourable = temperature > 0.0 && temperature < 50.0 && humidity >= critical;
+
+        if favourable {
+            self.dry_hours = 0;
+            // Growth slows down as the index nears what the humidity can sustain
+            let excess = (critical - humidity) / (critical - 100.0);
+            let max_index = 1.0 + 7.0 * excess - 2.0 * excess * excess;
+            let k1 = if self.mold_index < 1.0 { 1.0 } else { 2.0 };
+            let k2 = (1.0 - (2.3 * (self.mold_index - max_index)).exp()).max(0.0);
+            // Growth per day
+            let rate = k1 * k2
+                / (7.0
+                    * (-0.68 * temperature.ln() - 13.9 * humidity.min(100.0).ln() + 66.02).exp());
+            self.mold_index = (self.mold_index + rate / 24.0).min(6.0);
+        } else {
+            self.dry_hours = self.dry_hours.saturating_add(1);
+            // Mold recedes in dry spells, but not between their 6th and 24th hour
+            let decline = match self.dry_hours {
+                hours if hours <= 6 => 0.00133,
+                hours if hours <= 24 => 0.0,
+                _ => 0.000667,
+            };
+            self.mold_index = (self.mold_index - decline).max(0.0);
+        }
+
+        favourable
+    }
+}
+
+// Keep the mold index of every mapped sensor up to date in the background
+pub fn start(db_pool: &Pool<Postgres>) {
+    let db = db_pool.clone();
+    let interval = std::time::Duration::from_secs(env_or("MOLD_INTERVAL_SECS", 900));
+
+    tokio::spawn(async move {
+        loop {
+            match run(&db).await {
+                Ok(events) => {
+                    for event in &events {
+                        info!("Alert {:?}: {}", event.kind, event.message);
+                    }
+                }
+                Err(e) => error!("Mold index update failed: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+// Run the model over the hours completed since the last run, then evaluate
+// alert rules on the mold index
+async fn run(db: &Pool<Postgres>) -> Result<Vec<AlertEvent>, sqlx::Error> {
+    let until = Utc::now().duration_trunc(Duration::hours(1)).unwrap();
+    let backfill = Duration::days(env_or("MOLD_BACKFILL_DAYS", 90));
+    let default_temperature: f64 = env_or("MOLD_DEFAULT_TEMPERATURE", 20.0);
+    // Hours that are rolled up are read from the rollups, so readings count as
+    // seen once they are rolled up
+    let rolled = rollup::get_last_entry_id(db).await?;
+
+    let mut events = Vec::new();
+    for identifier in mold::get_identifiers(db).await? {
+        let fresh = MoldState {
+            unique_identifier: identifier.clone(),
+            mold_index: 0.0,
+            dry_hours: 0,
+            computed_until: until - backfill,
+            last_entry_id: 0,
+        };
+        let mut state = match mold::get_state(db, &identifier).await? {
+            // Readings for hours already computed change the whole history after
+            // them, so it is computed again
+            Some(state)
+                if mold::has_late_readings(
+                    db,
+                    &identifier,
+                    state.last_entry_id,
+                    state.computed_until,
+                )
+                .await? =>
+            {
+                fresh
+            }
+            Some(state) => state,
+            None => fresh,
+        };
+        if state.computed_until >= until {
+            continue;
+        }
+
+        // Hours without readings are skipped, as if no time passed
+        let climate =
+            mold::get_hourly_climate(db, &identifier, state.computed_until, until).await?;
+        let points: Vec<MoldPoint> = climate
+            .into_iter()
+            .map(|hour| {
+                let temperature = hour.temperature.unwrap_or(default_temperature);
+                let favourable = state.step(temperature, hour.humidity);
+                MoldPoint {
+                    hour: hour.hour,
+                    value: state.mold_index,
+                    humidity: hour.humidity,
+                    temperature,
+                    favourable,
+                }
+            })
+            .collect();
+        state.computed_until = until;
+        state.last_entry_id = rolled;
+        mold::save(db, &state, &points).await?;
+
+        // The index of an hour is known at its end
+        let values: Vec<_> = points
+            .iter()
+            .map(|point| (point.hour + Duration::hours(1), point.value))
+            .collect();
+        events.extend(alerts::evaluate_values(db, &identifier, MOLD_INDEX, &values).await?);
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(mold_index: f64) -> MoldState {
+        MoldState {
+            unique_identifier: "AA".to_string(),
+            mold_index,
+            dry_hours: 0,
+            computed_until: Utc::now(),
+            last_entry_id: 0,
+        }
+    }
+
+    #[test]
+    fn critical_humidity_levels_off_at_80_percent() {
+        assert!((critical_humidity(0.0) - 100.0).abs() < 1e-9);
+        assert!((critical_humidity(20.0) - 80.0).abs() < 0.1);
+        assert_eq!(critical_humidity(30.0), 80.0);
+        assert!(critical_humidity(10.0) > critical_humidity(15.0));
+    }
+
+    #[test]
+    fn first_growth_takes_as_long_as_the_reference_model() {
+        // Pine sapwood at 20°C and 97% shows first growth after
+        // exp(-0.68 ln 20 - 13.9 ln 97 + 66.02) = 1.48 weeks, or 249 hours
+        let mut state = state(0.0);
+        for _ in 0..240 {
+            assert!(state.step(20.0, 97.0));
+        }
+        assert!(state.mold_index < 1.0, "{}", state.mold_index);
+        for _ in 240..255 {
+            state.step(20.0, 97.0);
+        }
+        assert!(state.mold_index >= 1.0, "{}", state.mold_index);
+    }
+
+    #[test]
+    fn growth_stops_at_what_the_humidity_sustains() {
+        // 1 + 7 * 0.85 - 2 * 0.85² at 97%
+        let mut state = state(5.4);
+        for _ in 0..24 * 365 {
+            state.step(20.0, 97.0);
+        }
+        assert!(state.mold_index < 5.505, "{}", state.mold_index);
+        assert!(state.mold_index > 5.4, "{}", state.mold_index);
+    }
+
+    #[test]
+    fn no_growth_below_the_critical_humidity_or_outside_the_temperatures() {
+        for (temperature, humidity) in [(20.0, 79.0), (5.0, 85.0), (0.0, 100.0), (50.0, 100.0)] {
+            let mut state = state(0.0);
+            assert!(!state.step(temperature, humidity));
+            assert_eq!(state.mold_index, 0.0);
+            assert_eq!(state.dry_hours, 1);
+        }
+    }
+
+    #[test]
+    fn mold_recedes_in_dry_spells() {
+        let mut state = state(2.0);
+        for _ in 0..6 {
+            state.step(20.0, 50.0);
+        }
+        assert!((state.mold_index - (2.0 - 6.0 * 0.00133)).abs() < 1e-9);
+        let after_six = state.mold_index;
+        for _ in 6..24 {
+            state.step(20.0, 50.0);
+        }
+        assert_eq!(state.mold_index, after_six);
+        state.step(20.0, 50.0);
+        assert!((state.mold_index - (after_six - 0.000667)).abs() < 1e-9);
+        assert_eq!(state.dry_hours, 25);
+
+        // A favourable hour starts the dry spell over
+        state.step(20.0, 97.0);
+        assert_eq!(state.dry_hours, 0);
+    }
+}
@@ -0,0 +1,431 @@
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use reqwest::Url;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use sha2::Sha256;
+use sqlx::{Pool, Postgres, Transaction};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Notify;
+
+use crate::core::config::env_or;
+use crate::core::smtp::{self, SmtpConfig};
+use crate::models::alert::{AlertEvent, AlertEventKind};
+use crate::models::notification::{self, ChannelConfig, Notification, NotificationKind};
+
+// Deliveries claimed per round
+const BATCH_SIZE: i64 = 50;
+
+// How long a claimed delivery is held before another worker may retry it
+const LEASE_SECONDS: f64 = 120.0;
+
+// Wakes the worker when deliveries are queued, instead of waiting for the next poll
+static QUEUED: Notify = Notify::const_new();
+
+// Whether notifications may go to loopback and private networks, e.g. to local
+// stand-in servers while testing. Off by default, so users cannot make the server
+// reach internal services.
+fn private_targets_allowed() -> bool {
+    env_or("NOTIFY_ALLOW_PRIVATE_TARGETS", false)
+}
+
+// Whether an address is reachable on the public internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local and link-local addresses
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+// Check that a channel URL is one notifications may be sent to. Host names are
+// checked again when they are resolved, as they may point anywhere.
+pub fn check_target(url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|_| format!("Invalid URL: {}", url))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("Invalid URL: {}", url));
+    }
+    if private_targets_allowed() {
+        return Ok(());
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| format!("Invalid URL: {}", url))?
+        .to_lowercase();
+    // IPv6 hosts come in brackets
+    let private = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => !is_public(ip),
+        Err(_) => {
+            let host = host.trim_end_matches('.');
+            host == "localhost" || host.ends_with(".localhost")
+        }
+    };
+    match private {
+        true => Err(format!(
+            "Notifications cannot be sent to private addresses: {}",
+            url
+        )),
+        false => Ok(()),
+    }
+}
+
+// Resolves host names to their public addresses only, so a name pointing into the
+// private network is not followed
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| private_targets_allowed() || is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .dns_resolver(Arc::new(PublicResolver))
+            // A redirect could lead to a private address that was never checked
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to build HTTP client")
+    })
+}
+
+impl Notification {
+    pub fn from_event(event: &AlertEvent) -> Self {
+        let (kind, state) = match event.kind {
+            AlertEventKind::Fired => (NotificationKind::Fired, "Alert"),
+            AlertEventKind::Resolved => (NotificationKind::Resolved, "Resolved"),
+        };
+        Self {
+            kind,
+            title: format!("{}: {}", state, event.rule_name),
+            message: event.message.clone(),
+            rule_id: Some(event.rule_id),
+            unique_identifier: Some(event.unique_identifier.clone()),
+            value: event.value,
+            time: event.created_at,
+        }
+    }
+
+    pub fn test(channel_name: &str) -> Self {
+        Self {
+            kind: NotificationKind::Test,
+            title: "Test notification".to_string(),
+            message: format!("Notifications on {} are working", channel_name),
+            rule_id: None,
+            unique_identifier: None,
+            value: None,
+            time: Utc::now(),
+        }
+    }
+}
+
+// Sign a webhook body, so the receiver can check it came from us and is recent.
+// The signature covers "<timestamp>.<body>".
+fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// The response body is left out of the error, as it is shown to the user and
+// could hold anything the target chose to send
+fn check(response: Result<reqwest::Response, reqwest::Error>) -> Result<(), String> {
+    let response = response.map_err(|e| e.to_string())?;
+    let status = response.status();
+    match status.is_success() {
+        true => Ok(()),
+        false => Err(format!("Server responded with {}", status)),
+    }
+}
+
+// POST a notification as JSON to a webhook whose URL was checked already
+async fn send_webhook(
+    url: &str,
+    secret: Option<&str>,
+    notification: &Notification,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(notification).map_err(|e| e.to_string())?;
+    let timestamp = Utc::now().timestamp();
+    let mut request = client()
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Timestamp", timestamp);
+    if let Some(secret) = secret {
+        request = request.header("X-Webhook-Signature", signature(secret, timestamp, &body));
+    }
+    check(request.body(body).send().await)
+}
+
+// Send a notification through a channel once
+pub async fn send(config: &ChannelConfig, notification: &Notification) -> Result<(), String> {
+    let urgent = notification.kind == NotificationKind::Fired;
+
+    if let ChannelConfig::Webhook { url, .. }
+    | ChannelConfig::Ntfy { url, .. }
+    | ChannelConfig::Gotify { url, .. } = config
+    {
+        check_target(url)?;
+    }
+
+    match config {
+        ChannelConfig::Webhook { url, secret } => {
+            send_webhook(url, secret.as_deref(), notification).await
+        }
+        ChannelConfig::Email { to } => {
+            let config = SmtpConfig::from_env().ok_or("No SMTP relay configured")?;
+            smtp::send(&config, to, &notification.title, &notification.message).await
+        }
+        ChannelConfig::Ntfy { url, token } => {
+            let mut request = client()
+                .post(url)
+                .header("Title", &notification.title)
+                .header("Priority", if urgent { "high" } else { "default" });
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            check(request.body(notification.message.clone()).send().await)
+        }
+        ChannelConfig::Gotify { url, token } => {
+            let request = client()
+                .post(format!("{}/message", url.trim_end_matches('/')))
+                .header("X-Gotify-Key", token)
+                .json(&serde_json::json!({
+                    "title": notification.title,
+                    "message": notification.message,
+                    "priority": if urgent { 8 } else { 5 },
+                }));
+            check(request.send().await)
+        }
+    }
+}
+
+// Queue notifications for alerts that fired or resolved, on the channels of their
+// rules. Runs in the transaction that records the events, so none are lost.
+pub async fn enqueue_events(
+    tx: &mut Transaction<'_, Postgres>,
+    events: &[AlertEvent],
+) -> Result<(), sqlx::Error> {
+    for event in events {
+        let notification = Notification::from_event(event);
+        notification::enqueue_for_rule(tx, event.rule_id, Some(event.id), &notification).await?;
+    }
+
+    Ok(())
+}
+
+// Start delivering right away once queued notifications are committed
+pub fn wake() {
+    QUEUED.notify_one();
+}
+
+// Wait before the next attempt, doubling from 30 seconds up to an hour
+fn backoff(attempts: i32) -> Duration {
+    Duration::seconds((30i64 << attempts.clamp(0, 7)).min(3600))
+}
+
+// Send the deliveries that are due, returning how many were picked up
+async fn run(db: &Pool<Postgres>) -> Result<usize, sqlx::Error> {
+    let max_attempts: i32 = env_or("NOTIFY_MAX_ATTEMPTS", 5).max(1);
+    let due = notification::claim_due(db, BATCH_SIZE, LEASE_SECONDS).await?;
+
+    for delivery in &due {
+        match send(&delivery.config, &delivery.payload).await {
+            Ok(()) => notification::mark_delivered(db, delivery.id).await?,
+            Err(e) => {
+                let attempts = delivery.attempts + 1;
+                let retry_at =
+                    (attempts < max_attempts).then(|| Utc::now() + backoff(attempts - 1));
+                match retry_at {
+                    Some(retry_at) => warn!(
+                        "Notification {} failed, retrying at {}: {}",
+                        delivery.id, retry_at, e
+                    ),
+                    None => warn!(
+                        "Notification {} failed after {} attempts: {}",
+                        delivery.id, attempts, e
+                    ),
+                }
+                notification::mark_failed(db, delivery.id, &e, retry_at).await?;
+            }
+        }
+    }
+
+    Ok(due.len())
+}
+
+// Deliver queued notifications in the background, retrying failures with backoff
+pub fn start(db_pool: &Pool<Postgres>) {
+    let db = db_pool.clone();
+    let interval = std::time::Duration::from_secs(env_or("NOTIFY_POLL_SECS", 15));
+
+    tokio::spawn(async move {
+        info!("Notification worker started");
+        loop {
+            match run(&db).await {
+                // A full batch means more may be due right away
+                Ok(claimed) if claimed as i64 == BATCH_SIZE => continue,
+                Ok(_) => {}
+                Err(e) => error!("Notification delivery failed: {}", e),
+            }
+            let _ = tokio::time::timeout(interval, QUEUED.notified()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn signature_is_hmac_of_timestamp_and_body() {
+        assert_eq!(
+            signature("secret", 1700000000, br#"{"a":1}"#),
+            "sha256=49f24e537407743fa4a0242bb63b94b9a47ee99cbbe071ccd8a22550ae411686"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        assert_eq!(backoff(0), Duration::seconds(30));
+        assert_eq!(backoff(1), Duration::seconds(60));
+        assert_eq!(backoff(6), Duration::seconds(1920));
+        assert_eq!(backoff(7), Duration::seconds(3600));
+        assert_eq!(backoff(50), Duration::seconds(3600));
+        assert_eq!(backoff(-1), Duration::seconds(30));
+    }
+
+    #[test]
+    fn check_target_rejects_private_addresses() {
+        assert!(check_target("https://example.com/hook").is_ok());
+        assert!(check_target("http://93.184.216.34/hook").is_ok());
+        assert!(check_target("ftp://example.com/hook").is_err());
+        assert!(check_target("http://127.0.0.1:8080/hook").is_err());
+        assert!(check_target("http://10.0.0.1/hook").is_err());
+        assert!(check_target("http://169.254.169.254/latest").is_err());
+        assert!(check_target("http://[::1]/hook").is_err());
+        assert!(check_target("http://[::ffff:192.168.1.1]/hook").is_err());
+        assert!(check_target("http://LOCALHOST./hook").is_err());
+    }
+
+    // Answer one request with the given status line, returning the request
+    async fn stand_in(status: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+            // Read until the headers and the body they announce are in
+            loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(str::to_string)
+                        })
+                        .and_then(|length| length.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+                if read == 0 {
+                    break;
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 6\r\nConnection: close\r\n\r\nsecret",
+                status
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_signed_json() {
+        let (url, handle) = stand_in("200 OK").await;
+        let notification = Notification::test("hook");
+
+        send_webhook(&url, Some("secret"), &notification)
+            .await
+            .unwrap();
+
+        let request = handle.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let header = |name: &str| {
+            head.lines()
+                .find_map(|line| {
+                    let (key, value) = line.split_once(": ")?;
+                    key.eq_ignore_ascii_case(name).then(|| value.to_string())
+                })
+                .unwrap()
+        };
+        assert!(head.starts_with("POST /hook "));
+        assert_eq!(header("content-type"), "application/json");
+        let timestamp: i64 = header("x-webhook-timestamp").parse().unwrap();
+        assert_eq!(
+            header("x-webhook-signature"),
+            signature("secret", timestamp, body.as_bytes())
+        );
+        let sent: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(sent["title"], "Test notification");
+    }
+
+    #[tokio::test]
+    async fn webhook_error_leaves_out_the_response_body() {
+        let (url, handle) = stand_in("500 Internal Server Error").await;
+
+        let error = send_webhook(&url, None, &Notification::test("hook"))
+            .await
+            .unwrap_err();
+
+        handle.await.unwrap();
+        assert_eq!(error, "Server responded with 500 Internal Server Error");
+    }
+}
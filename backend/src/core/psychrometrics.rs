@@ -0,0 +1,187 @@
+use crate::models::data_entry::{
+    ABSOLUTE_HUMIDITY, DEW_POINT, HUMIDEX, HUMIDITY, Metric, NewDataEntry, TEMPERATURE,
+    default_unit,
+};
+
+// Magnus formula coefficients over water (Sonntag, 1990), good from -45°C to 60°C
+const MAGNUS_B: f64 = 17.62;
+const MAGNUS_C: f64 = 243.12;
+
+// Saturation vapour pressure over water in hPa
+fn saturation_pressure(temperature: f64) -> f64 {
+    6.112 * (MAGNUS_B * temperature / (MAGNUS_C + temperature)).exp()
+}
+
+// Temperature in °C at which the air would be saturated
+pub fn dew_point(temperature: f64, humidity: f64) -> f64 {
+    let gamma = (humidity / 100.0).ln() + MAGNUS_B * temperature / (MAGNUS_C + temperature);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+// Water vapour in the air in g/m³, comparable between rooms at different temperatures
+pub fn absolute_humidity(temperature: f64, humidity: f64) -> f64 {
+    // 216.7 g·K/(hPa·m³) is the molar mass of water over the gas constant
+    216.7 * saturation_pressure(temperature) * humidity / 100.0 / (273.15 + temperature)
+}
+
+// How hot humid air feels, on the Canadian humidex scale
+pub fn humidex(temperature: f64, dew_point: f64) -> f64 {
+    let vapour_pressure = 6.11 * (5417.7530 * (1.0 / 273.16 - 1.0 / (273.15 + dew_point))).exp();
+    temperature + 0.5555 * (vapour_pressure - 10.0)
+}
+
+// Whether water condenses on a surface at the given temperature, None when the
+// dew point or the surface temperature is unknown
+pub fn condensation_risk(dew_point: Option<f64>, surface_temperature: Option<f64>) -> Option<bool> {
+    Some(dew_point? >= surface_temperature?)
+}
+
+// Whether a metric is in the unit the formulas expect, with no unit meaning the default
+fn in_default_unit(metric: &Metric) -> bool {
+    metric.unit.is_none() || metric.unit == default_unit(&metric.name)
+}
+
+impl NewDataEntry {
+    // Add dew point, absolute humidity and humidex to a reading with both humidity
+    // and temperature. Metrics the reading already carries are kept as they are.
+    pub fn add_derived_metrics(&mut self) {
+        let find = |name: &str| {
+            self.metrics
+                .iter()
+                .find(|metric| metric.name == name && in_default_unit(metric))
+                .map(|metric| metric.value)
+        };
+        let (Some(humidity), Some(temperature)) = (find(HUMIDITY), find(TEMPERATURE)) else {
+            return;
+        };
+        if humidity <= 0.0 || humidity > 100.0 || temperature <= -MAGNUS_C {
+            return;
+        }
+
+        let dew_point = dew_point(temperature, humidity);
+        let derived = [
+            (DEW_POINT, dew_point),
+            (ABSOLUTE_HUMIDITY, absolute_humidity(temperature, humidity)),
+            (HUMIDEX, humidex(temperature, dew_point)),
+        ];
+        for (name, value) in derived {
+            if self.metrics.iter().any(|metric| metric.name == name) {
+                continue;
+            }
+            self.metrics.push(Metric {
+                name: name.to_string(),
+                value,
+                unit: default_unit(name),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn dew_point_matches_reference_values() {
+        assert_near(dew_point(20.0, 50.0), 9.3, 0.1);
+        assert_near(dew_point(25.0, 60.0), 16.7, 0.1);
+        assert_near(dew_point(0.0, 80.0), -3.0, 0.1);
+        // Saturated air is at its dew point
+        assert_near(dew_point(10.0, 100.0), 10.0, 1e-9);
+    }
+
+    #[test]
+    fn absolute_humidity_matches_reference_values() {
+        assert_near(absolute_humidity(20.0, 50.0), 8.65, 0.05);
+        assert_near(absolute_humidity(25.0, 100.0), 23.0, 0.1);
+        assert_near(absolute_humidity(0.0, 100.0), 4.85, 0.05);
+        assert_near(absolute_humidity(30.0, 80.0), 24.3, 0.1);
+    }
+
+    #[test]
+    fn humidex_matches_reference_values() {
+        assert_near(humidex(30.0, 15.0), 34.0, 0.5);
+        assert_near(humidex(30.0, 25.0), 42.0, 0.5);
+    }
+
+    #[test]
+    fn condensation_risk_needs_both_temperatures() {
+        assert_eq!(condensation_risk(Some(12.0), Some(11.9)), Some(true));
+        assert_eq!(condensation_risk(Some(12.0), Some(12.0)), Some(true));
+        assert_eq!(condensation_risk(Some(12.0), Some(15.0)), Some(false));
+        assert_eq!(condensation_risk(None, Some(15.0)), None);
+        assert_eq!(condensation_risk(Some(12.0), None), None);
+    }
+
+    fn entry(metrics: &[(&str, f64, Option<&str>)]) -> NewDataEntry {
+        NewDataEntry {
+            unique_identifier: "AA".to_string(),
+            created_at: chrono::DateTime::from_timestamp(1700000000, 0).unwrap(),
+            topic: None,
+            metrics: metrics
+                .iter()
+                .map(|&(name, value, unit)| Metric {
+                    name: name.to_string(),
+                    value,
+                    unit: unit.map(|unit| unit.to_string()),
+                })
+                .collect(),
+        }
+    }
+
+    fn names(entry: &NewDataEntry) -> Vec<&str> {
+        entry
+            .metrics
+            .iter()
+            .map(|metric| metric.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn derives_metrics_from_humidity_and_temperature() {
+        let mut reading = entry(&[(HUMIDITY, 50.0, None), (TEMPERATURE, 20.0, Some("°C"))]);
+        reading.add_derived_metrics();
+        assert_eq!(
+            names(&reading),
+            [HUMIDITY, TEMPERATURE, DEW_POINT, ABSOLUTE_HUMIDITY, HUMIDEX]
+        );
+        assert_near(reading.metrics[2].value, 9.3, 0.1);
+        assert_eq!(reading.metrics[3].unit.as_deref(), Some("g/m³"));
+
+        // Metrics sent by the sensor are kept
+        let mut reading = entry(&[
+            (HUMIDITY, 50.0, None),
+            (TEMPERATURE, 20.0, None),
+            (DEW_POINT, 1.0, None),
+        ]);
+        reading.add_derived_metrics();
+        assert_eq!(reading.metrics[2].value, 1.0);
+        assert_eq!(reading.metrics.len(), 5);
+    }
+
+    #[test]
+    fn derives_nothing_from_unusable_readings() {
+        let unusable = [
+            entry(&[(HUMIDITY, 50.0, None)]),
+            entry(&[(HUMIDITY, 50.0, None), (TEMPERATURE, 68.0, Some("°F"))]),
+            entry(&[(HUMIDITY, 0.0, None), (TEMPERATURE, 20.0, None)]),
+            entry(&[(HUMIDITY, 101.0, None), (TEMPERATURE, 20.0, None)]),
+            entry(&[(HUMIDITY, 50.0, None), (TEMPERATURE, -MAGNUS_C, None)]),
+        ];
+        for mut reading in unusable {
+            let before = reading.metrics.len();
+            reading.add_derived_metrics();
+            assert_eq!(reading.metrics.len(), before, "{:?}", names(&reading));
+        }
+    }
+}
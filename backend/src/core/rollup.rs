@@ -0,0 +1,87 @@
+use chrono::{Duration, DurationRound, Utc};
+use log::{error, info};
+use sqlx::{Pool, Postgres};
+
+use crate::core::config::env_or;
+use crate::models::rollup::{self, Retention};
+
+// Readings added to the rollups per transaction
+const DEFAULT_BATCH_SIZE: i32 = 50_000;
+
+// Raw readings deleted per statement
+const DELETE_BATCH_SIZE: i64 = 10_000;
+
+// Keep the hourly and daily rollups up to date and apply retention in the background
+pub fn start(db_pool: &Pool<Postgres>) {
+    let db = db_pool.clone();
+    let interval = std::time::Duration::from_secs(env_or("ROLLUP_INTERVAL_SECS", 300));
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = run(&db).await {
+                error!("Rollup failed: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+// How often to check whether the transactions a rollup waits for are done
+const WRITER_POLL_MS: u64 = 100;
+
+// Largest reading id that is safe to roll up. Waits for the transactions still
+// inserting readings with smaller ids, without locking the table, which would
+// hold up ingest behind them.
+async fn get_committed_entry_id(db: &Pool<Postgres>) -> Result<i32, sqlx::Error> {
+    let bound = rollup::get_entry_bound(db).await?;
+    while !bound.writers.is_empty() && rollup::any_running(db, &bound.writers).await? {
+        tokio::time::sleep(std::time::Duration::from_millis(WRITER_POLL_MS)).await;
+    }
+
+    Ok(bound.max_id)
+}
+
+// Roll up everything stored so far, then drop what is past its retention
+async fn run(db: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let started = Utc::now();
+    let batch_size = env_or("ROLLUP_BATCH_SIZE", DEFAULT_BATCH_SIZE).max(1);
+
+    let committed = get_committed_entry_id(db).await?;
+    let mut last = rollup::get_last_entry_id(db).await?;
+    while last < committed {
+        let next = last.saturating_add(batch_size).min(committed);
+        rollup::roll_up(db, last, next).await?;
+        last = next;
+    }
+    // Readings stored from now on may still belong to the current hour
+    rollup::set_rolled_until(db, started.duration_trunc(Duration::hours(1)).unwrap()).await?;
+
+    let retention = Retention::from_env();
+    if let Some(cutoff) = Retention::cutoff(retention.raw, started) {
+        let mut deleted = 0;
+        loop {
+            let rows = rollup::delete_raw_before(db, cutoff, DELETE_BATCH_SIZE).await?;
+            deleted += rows;
+            if rows < DELETE_BATCH_SIZE as u64 {
+                break;
+            }
+        }
+        if deleted > 0 {
+            info!("Deleted {} raw readings from before {}", deleted, cutoff);
+        }
+    }
+    if let Some(cutoff) = Retention::cutoff(retention.hourly, started) {
+        let deleted = rollup::delete_hourly_before(db, cutoff).await?;
+        if deleted > 0 {
+            info!("Deleted {} hourly rollups from before {}", deleted, cutoff);
+        }
+    }
+    if let Some(cutoff) = Retention::cutoff(retention.daily, started) {
+        let deleted = rollup::delete_daily_before(db, cutoff).await?;
+        if deleted > 0 {
+            info!("Deleted {} daily rollups from before {}", deleted, cutoff);
+        }
+    }
+
+    Ok(())
+}
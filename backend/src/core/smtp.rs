@@ -0,0 +1,333 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::env;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_native_tls::{TlsConnector, native_tls};
+
+use crate::core::config::env_or;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// How the connection to the relay is secured
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmtpSecurity {
+    None,
+    // Upgrade a plain connection, usually on port 587
+    StartTls,
+    // TLS from the start, usually on port 465
+    Tls,
+}
+
+// The relay email notifications are sent through
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub security: SmtpSecurity,
+}
+
+impl SmtpConfig {
+    // None when no relay is configured
+    pub fn from_env() -> Option<Self> {
+        let host = env::var("SMTP_HOST").ok().filter(|host| !host.is_empty())?;
+        let security = match env::var("SMTP_SECURITY").as_deref() {
+            Ok("none") => SmtpSecurity::None,
+            Ok("tls") => SmtpSecurity::Tls,
+            _ => SmtpSecurity::StartTls,
+        };
+        let default_port = match security {
+            SmtpSecurity::None => 25,
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+        };
+
+        Some(Self {
+            port: env_or("SMTP_PORT", default_port),
+            username: env::var("SMTP_USERNAME").ok(),
+            password: env::var("SMTP_PASSWORD").ok(),
+            from: env::var("SMTP_FROM").unwrap_or_else(|_| format!("alerts@{}", host)),
+            security,
+            host,
+        })
+    }
+}
+
+// One SMTP conversation over a plain or TLS stream
+struct Session<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    // Read a possibly multi-line reply and check its code
+    async fn expect(&mut self, codes: &[u16]) -> Result<String, String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self
+                .stream
+                .read_line(&mut line)
+                .await
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                return Err("Connection closed by the SMTP server".to_string());
+            }
+            reply.push_str(&line);
+            // "250-..." continues, "250 ..." ends the reply
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                let code = line
+                    .get(..3)
+                    .and_then(|code| code.parse().ok())
+                    .unwrap_or(0);
+                if codes.contains(&code) {
+                    return Ok(reply);
+                }
+                return Err(format!("SMTP server replied: {}", reply.trim_end()));
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str, codes: &[u16]) -> Result<String, String> {
+        self.stream
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        self.expect(codes).await
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+}
+
+// Whether an address can be put in the envelope as it is. Whitespace and angle
+// brackets would let it end the MAIL FROM or RCPT TO path and add parameters.
+pub fn is_valid_address(address: &str) -> bool {
+    let Some((local, domain)) = address.rsplit_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.is_empty()
+        && address.len() <= 254
+        && !address
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '<' || c == '>')
+}
+
+// Strip line breaks, so values cannot add headers of their own
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+// Encode a header as UTF-8 when it is not plain ASCII
+fn encode_header(value: &str) -> String {
+    let value = header_value(value);
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?UTF-8?B?{}?=", BASE64.encode(value))
+    }
+}
+
+fn message(config: &SmtpConfig, to: &str, subject: &str, body: &str) -> String {
+    // Lines starting with a dot are doubled, as a lone dot ends the message
+    let body: Vec<String> = body
+        .lines()
+        .map(|line| match line.starts_with('.') {
+            true => format!(".{}", line),
+            false => line.to_string(),
+        })
+        .collect();
+
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n.",
+        header_value(&config.from),
+        header_value(to),
+        encode_header(subject),
+        chrono::Utc::now().to_rfc2822(),
+        body.join("\r\n")
+    )
+}
+
+async fn hello<S: AsyncRead + AsyncWrite + Unpin>(session: &mut Session<S>) -> Result<(), String> {
+    let name = env::var("SMTP_HELO_NAME").unwrap_or_else(|_| "localhost".to_string());
+    session.command(&format!("EHLO {}", name), &[250]).await?;
+    Ok(())
+}
+
+// Authenticate and hand over the message, after the greeting and EHLO
+async fn transact<S: AsyncRead + AsyncWrite + Unpin>(
+    mut session: Session<S>,
+    config: &SmtpConfig,
+    to: &str,
+    subject: &str,
+    body: &str,
+) -> Result<(), String> {
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        let credentials = BASE64.encode(format!("\0{}\0{}", username, password));
+        session
+            .command(&format!("AUTH PLAIN {}", credentials), &[235])
+            .await?;
+    }
+    session
+        .command(
+            &format!("MAIL FROM:<{}>", header_value(&config.from)),
+            &[250],
+        )
+        .await?;
+    session
+        .command(&format!("RCPT TO:<{}>", header_value(to)), &[250, 251])
+        .await?;
+    session.command("DATA", &[354]).await?;
+    session
+        .command(&message(config, to, subject, body), &[250])
+        .await?;
+    // The message is accepted, so a failing QUIT does not matter
+    let _ = session.command("QUIT", &[221]).await;
+
+    Ok(())
+}
+
+async fn tls(
+    config: &SmtpConfig,
+    stream: TcpStream,
+) -> Result<tokio_native_tls::TlsStream<TcpStream>, String> {
+    let connector = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+    TlsConnector::from(connector)
+        .connect(&config.host, stream)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn deliver(config: &SmtpConfig, to: &str, subject: &str, body: &str) -> Result<(), String> {
+    let stream = TcpStream::connect((config.host.as_str(), config.port))
+        .await
+        .map_err(|e| format!("Cannot connect to {}:{}: {}", config.host, config.port, e))?;
+
+    match config.security {
+        SmtpSecurity::None => {
+            let mut session = Session::new(stream);
+            session.expect(&[220]).await?;
+            hello(&mut session).await?;
+            transact(session, config, to, subject, body).await
+        }
+        SmtpSecurity::Tls => {
+            let mut session = Session::new(tls(config, stream).await?);
+            session.expect(&[220]).await?;
+            hello(&mut session).await?;
+            transact(session, config, to, subject, body).await
+        }
+        SmtpSecurity::StartTls => {
+            let mut session = Session::new(stream);
+            session.expect(&[220]).await?;
+            hello(&mut session).await?;
+            session.command("STARTTLS", &[220]).await?;
+            let mut session = Session::new(tls(config, session.into_inner()).await?);
+            hello(&mut session).await?;
+            transact(session, config, to, subject, body).await
+        }
+    }
+}
+
+// Send a plain text email
+pub async fn send(config: &SmtpConfig, to: &str, subject: &str, body: &str) -> Result<(), String> {
+    // Channels are checked when they are saved, SMTP_FROM is not
+    for address in [&config.from, to] {
+        if !is_valid_address(address) {
+            return Err(format!("Invalid email address: {:?}", address));
+        }
+    }
+    tokio::time::timeout(TIMEOUT, deliver(config, to, subject, body))
+        .await
+        .map_err(|_| "SMTP server timed out".to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SmtpConfig {
+        SmtpConfig {
+            host: "localhost".to_string(),
+            port: 25,
+            username: None,
+            password: None,
+            from: "alerts@localhost".to_string(),
+            security: SmtpSecurity::None,
+        }
+    }
+
+    #[test]
+    fn message_doubles_leading_dots() {
+        let message = message(
+            &config(),
+            "to@example.com",
+            "Alert",
+            ".hidden\nplain\n.\nend",
+        );
+
+        let (_, body) = message.split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, "..hidden\r\nplain\r\n..\r\nend\r\n.");
+    }
+
+    #[test]
+    fn message_headers_cannot_be_injected() {
+        let message = message(
+            &config(),
+            "to@example.com\r\nBcc: other@example.com",
+            "Alert\r\nBcc: other@example.com",
+            "body",
+        );
+
+        let (head, _) = message.split_once("\r\n\r\n").unwrap();
+        assert!(!head.lines().any(|line| line.starts_with("Bcc:")));
+        assert!(head.contains("To: to@example.com  Bcc: other@example.com\r\n"));
+    }
+
+    #[test]
+    fn addresses_cannot_extend_the_envelope() {
+        assert!(is_valid_address("to@example.com"));
+        assert!(is_valid_address("first.last+alerts@mail.example.com"));
+        assert!(!is_valid_address("to.example.com"));
+        assert!(!is_valid_address("@example.com"));
+        assert!(!is_valid_address("to@"));
+        assert!(!is_valid_address("to@example.com> SIZE=1"));
+        assert!(!is_valid_address("<to@example.com>"));
+        assert!(!is_valid_address("to@example.com NOTIFY=NEVER"));
+        assert!(!is_valid_address("to@example.com\tx"));
+        assert!(!is_valid_address(
+            "to@example.com\r\nRCPT TO:<other@example.com>"
+        ));
+        assert!(!is_valid_address(&format!(
+            "{}@example.com",
+            "a".repeat(250)
+        )));
+    }
+
+    #[tokio::test]
+    async fn send_refuses_invalid_addresses_before_connecting() {
+        let mut from = config();
+        from.from = "alerts@localhost> AUTH=<>".to_string();
+        // Nothing listens on the port, so reaching the relay would fail differently
+        from.port = 9;
+        assert_eq!(
+            send(&from, "to@example.com", "Alert", "body").await,
+            Err(r#"Invalid email address: "alerts@localhost> AUTH=<>""#.to_string())
+        );
+        assert_eq!(
+            send(&config(), "to@example.com x", "Alert", "body").await,
+            Err(r#"Invalid email address: "to@example.com x""#.to_string())
+        );
+    }
+}
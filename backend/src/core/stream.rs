@@ -0,0 +1,106 @@
+use log::warn;
+use sqlx::{Pool, Postgres};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::core::config::env_or;
+use crate::models::data_entry::DataEntry;
+use crate::models::data_entry_mapping::get_all_for_user;
+
+// How often a subscriber reloads its mappings, so claims and transfers take effect
+const MAPPING_REFRESH: Duration = Duration::from_secs(30);
+
+// Fans out stored readings to live subscribers
+pub type LiveReadings = broadcast::Sender<Arc<DataEntry>>;
+
+pub fn channel() -> LiveReadings {
+    let capacity: usize = env_or("STREAM_BUFFER_SIZE", 1024);
+    broadcast::channel(capacity.max(1)).0
+}
+
+// A user's view of the live readings, limited to the sensors they have mapped
+pub struct Subscription {
+    db: Pool<Postgres>,
+    user_id: i32,
+    // Identifiers the subscriber asked for, all mapped sensors when unset
+    filter: Option<HashSet<String>>,
+    labels: HashMap<String, String>,
+    loaded_at: Instant,
+    receiver: broadcast::Receiver<Arc<DataEntry>>,
+}
+
+impl Subscription {
+    pub async fn new(
+        db: &Pool<Postgres>,
+        live: &LiveReadings,
+        user_id: i32,
+        filter: Option<HashSet<String>>,
+    ) -> Result<Self, sqlx::Error> {
+        // Subscribe first so nothing stored while the mappings load is missed
+        let receiver = live.subscribe();
+        let mut subscription = Self {
+            db: db.clone(),
+            user_id,
+            filter,
+            labels: HashMap::new(),
+            loaded_at: Instant::now(),
+            receiver,
+        };
+        subscription.load_mappings().await?;
+
+        Ok(subscription)
+    }
+
+    async fn load_mappings(&mut self) -> Result<(), sqlx::Error> {
+        self.labels = get_all_for_user(&self.db, self.user_id)
+            .await?
+            .into_iter()
+            .map(|mapping| (mapping.unique_identifier, mapping.label))
+            .collect();
+        self.loaded_at = Instant::now();
+
+        Ok(())
+    }
+
+    // Wait for the next reading this subscriber may see, labelled with their own
+    // mapping. Returns None once the server shuts down.
+    pub async fn next(&mut self) -> Option<DataEntry> {
+        loop {
+            let entry = match self.receiver.recv().await {
+                Ok(entry) => entry,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Live subscriber of user {} fell behind, skipped {} readings",
+                        self.user_id, skipped
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            };
+
+            if self.loaded_at.elapsed() >= MAPPING_REFRESH
+                && let Err(e) = self.load_mappings().await
+            {
+                warn!("Failed to reload mappings for live subscriber: {}", e);
+            }
+
+            if self
+                .filter
+                .as_ref()
+                .is_some_and(|filter| !filter.contains(&entry.unique_identifier))
+            {
+                continue;
+            }
+            let Some(label) = self.labels.get(&entry.unique_identifier) else {
+                continue;
+            };
+
+            return Some(DataEntry {
+                label: Some(label.clone()),
+                ..(*entry).clone()
+            });
+        }
+    }
+}
@@ -0,0 +1,523 @@
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use log::{error, info};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use crate::core::config::env_or;
+use crate::core::notify;
+use crate::core::weather::forecast_hours;
+use crate::models::alert::{self, AlertEvent, AlertEventKind, AlertState};
+use crate::models::app_user;
+use crate::models::data_entry::Round;
+use crate::models::sensor::{self, LatestReading, SensorStatus};
+use crate::models::ventilation::{Climate, Recommendation, VentilationAdvice};
+use crate::models::weather::{self, ForecastHour};
+
+// Measured outdoor weather older than this is not used, the forecast is instead
+const OUTDOOR_MAX_AGE_HOURS: i64 = 2;
+
+#[derive(Clone, Copy)]
+pub struct VentilationSettings {
+    // Relative humidity in % below which a room needs no airing
+    pub target_humidity: f64,
+    // How much drier in g/m³ outdoor air has to be for airing out to help
+    pub min_difference: f64,
+    // How far ahead the forecast is looked at
+    pub forecast_hours: i64,
+}
+
+impl VentilationSettings {
+    pub fn from_env() -> Self {
+        Self {
+            target_humidity: env_or("VENTILATION_TARGET_HUMIDITY", 60.0),
+            min_difference: env_or("VENTILATION_MIN_DIFFERENCE", 1.0),
+            forecast_hours: env_or("VENTILATION_FORECAST_HOURS", 6),
+        }
+    }
+}
+
+// What the recommendations of a user's rooms are based on
+struct Conditions {
+    rooms: Vec<LatestReading>,
+    outdoor: Option<Climate>,
+    forecast: Vec<ForecastHour>,
+    // Times in the reasons are given in the user's timezone
+    timezone: Tz,
+}
+
+async fn get_conditions(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    settings: &VentilationSettings,
+    now: DateTime<Utc>,
+) -> Result<Conditions, sqlx::Error> {
+    // Names Postgres knows but chrono-tz does not fall back to UTC
+    let timezone = app_user::get_by_id(db, user_id)
+        .await?
+        .and_then(|user| user.timezone.parse().ok())
+        .unwrap_or(Tz::UTC);
+    let (virtual_sensors, rooms): (Vec<LatestReading>, Vec<LatestReading>) =
+        sensor::get_latest_for_user(db, user_id)
+            .await?
+            .into_iter()
+            .partition(|reading| weather::is_virtual(&reading.unique_identifier));
+
+    let Some(location) = weather::get_for_user(db, user_id).await? else {
+        return Ok(Conditions {
+            rooms,
+            outdoor: None,
+            forecast: Vec::new(),
+            timezone,
+        });
+    };
+
+    let horizon = now + Duration::hours(settings.forecast_hours);
+    let forecast: Vec<ForecastHour> =
+        forecast_hours(weather::get_forecast(db, &location.unique_identifier, now).await?)
+            .into_iter()
+            .filter(|hour| hour.time <= horizon)
+            .collect();
+
+    // The newest measured hour, or the forecast for the coming hour without one
+    let outdoor = virtual_sensors
+        .iter()
+        .find(|reading| reading.unique_identifier == location.unique_identifier)
+        .and_then(|reading| Climate::from_metrics(reading.created_at?, &reading.metrics))
+        .filter(|climate| now - climate.time <= Duration::hours(OUTDOOR_MAX_AGE_HOURS))
+        .or_else(|| {
+            forecast
+                .first()
+                .filter(|hour| hour.time - now <= Duration::hours(1))
+                .map(Climate::from)
+        });
+
+    Ok(Conditions {
+        rooms,
+        outdoor,
+        forecast,
+        timezone,
+    })
+}
+
+// Whether to open the window of a room. Airing out only dries a room when the
+// outdoor air holds less water per m³ than the indoor air, whatever the relative
+// humidity outside.
+fn recommend(
+    room: &LatestReading,
+    outdoor: Option<&Climate>,
+    forecast: &[ForecastHour],
+    settings: &VentilationSettings,
+    timezone: Tz,
+) -> Recommendation {
+    let indoor = match room.status {
+        SensorStatus::Offline => None,
+        _ => room
+            .created_at
+            .and_then(|time| Climate::from_metrics(time, &room.metrics)),
+    };
+    let mut recommendation = Recommendation {
+        mapping_id: room.mapping_id,
+        unique_identifier: room.unique_identifier.clone(),
+        label: room.label.clone(),
+        advice: VentilationAdvice::Unknown,
+        reason: String::new(),
+        indoor: indoor.clone(),
+        outdoor: outdoor.cloned(),
+        difference: None,
+        best_time: None,
+    };
+
+    let Some(indoor) = indoor else {
+        recommendation.reason =
+            "No recent indoor reading with temperature and humidity".to_string();
+        return recommendation;
+    };
+    let Some(outdoor) = outdoor else {
+        recommendation.reason = "No recent outdoor weather, set a location to get it".to_string();
+        return recommendation;
+    };
+
+    let difference = (indoor.absolute_humidity - outdoor.absolute_humidity).to_2_decimal();
+    recommendation.difference = Some(difference);
+    let helps = |absolute_humidity: f64| {
+        indoor.absolute_humidity - absolute_humidity >= settings.min_difference
+    };
+    let comparison = format!(
+        "Outdoor air holds {} g/m³ of water against {} g/m³ inside",
+        outdoor.absolute_humidity, indoor.absolute_humidity
+    );
+
+    if indoor.humidity < settings.target_humidity {
+        recommendation.advice = VentilationAdvice::KeepClosed;
+        recommendation.reason = format!(
+            "Humidity inside is {}%, below {}%, so there is no need to air out",
+            indoor.humidity, settings.target_humidity
+        );
+    } else if helps(outdoor.absolute_humidity) {
+        recommendation.advice = VentilationAdvice::Open;
+        recommendation.reason = format!("{}, so airing out dries the room", comparison);
+        if let Some(hour) = forecast.iter().find(|hour| !helps(hour.absolute_humidity)) {
+            recommendation.reason += &format!(
+                ", best before {} when it gets more humid outside",
+                hour.time.with_timezone(&timezone).format("%H:%M %Z")
+            );
+        }
+    } else if let Some(hour) = forecast
+        .iter()
+        .filter(|hour| helps(hour.absolute_humidity))
+        .min_by(|a, b| a.absolute_humidity.total_cmp(&b.absolute_humidity))
+    {
+        recommendation.advice = VentilationAdvice::Wait;
+        recommendation.best_time = Some(hour.time);
+        recommendation.reason = format!(
+            "{}, but it is forecast to drop to {} g/m³ at {}",
+            comparison,
+            hour.absolute_humidity,
+            hour.time.with_timezone(&timezone).format("%H:%M %Z")
+        );
+    } else {
+        recommendation.advice = VentilationAdvice::KeepClosed;
+        recommendation.reason = format!("{}, so airing out would not dry the room", comparison);
+    }
+
+    recommendation
+}
+
+// Get whether to open the window of each room the user has mapped
+pub async fn get_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+) -> Result<Vec<Recommendation>, sqlx::Error> {
+    let settings = VentilationSettings::from_env();
+    let conditions = get_conditions(db, user_id, &settings, Utc::now()).await?;
+
+    Ok(conditions
+        .rooms
+        .iter()
+        .map(|room| {
+            recommend(
+                room,
+                conditions.outdoor.as_ref(),
+                &conditions.forecast,
+                &settings,
+                conditions.timezone,
+            )
+        })
+        .collect())
+}
+
+// Fire ventilation rules when airing out a room would help, and resolve them
+// once it no longer does
+pub fn start(db_pool: &Pool<Postgres>) {
+    let db = db_pool.clone();
+    let interval = std::time::Duration::from_secs(env_or("VENTILATION_INTERVAL_SECS", 900));
+
+    tokio::spawn(async move {
+        loop {
+            match run(&db).await {
+                Ok(events) => {
+                    for event in &events {
+                        info!("Alert {:?}: {}", event.kind, event.message);
+                    }
+                }
+                Err(e) => error!("Ventilation check failed: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+async fn run(db: &Pool<Postgres>) -> Result<Vec<AlertEvent>, sqlx::Error> {
+    let now = Utc::now();
+    let settings = VentilationSettings::from_env();
+    let mut tx = db.begin().await?;
+    let rules = alert::lock_ventilation_rules(&mut tx).await?;
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut conditions: HashMap<i32, Conditions> = HashMap::new();
+    let mut events = Vec::new();
+    for mut rule in rules {
+        if let Entry::Vacant(entry) = conditions.entry(rule.user_id) {
+            entry.insert(get_conditions(db, rule.user_id, &settings, now).await?);
+        }
+        let user_conditions = &conditions[&rule.user_id];
+        // Rules on sensors the user no longer has mapped stay as they are
+        let Some(room) = user_conditions
+            .rooms
+            .iter()
+            .find(|room| room.unique_identifier == rule.unique_identifier)
+        else {
+            continue;
+        };
+
+        // A firing rule only resolves once outdoor air is drier by less than the
+        // threshold minus the hysteresis, so it does not flap
+        let min_difference = match rule.state {
+            AlertState::Firing => rule.threshold - rule.hysteresis,
+            _ => rule.threshold,
+        };
+        let recommendation = recommend(
+            room,
+            user_conditions.outdoor.as_ref(),
+            &user_conditions.forecast,
+            &VentilationSettings {
+                min_difference,
+                ..settings
+            },
+            user_conditions.timezone,
+        );
+
+        let kind = match (recommendation.advice, rule.state) {
+            // Without readings or weather the rule keeps its state
+            (VentilationAdvice::Unknown, _) => continue,
+            (VentilationAdvice::Open, AlertState::Firing) => None,
+            (VentilationAdvice::Open, _) if rule.cooling_down(now) => None,
+            (VentilationAdvice::Open, _) => Some(AlertEventKind::Fired),
+            (_, AlertState::Firing) => Some(AlertEventKind::Resolved),
+            _ => None,
+        };
+
+        rule.last_value = recommendation.difference;
+        rule.evaluated_at = Some(now);
+        if let Some(kind) = kind {
+            let message = match kind {
+                AlertEventKind::Fired => {
+                    rule.state = AlertState::Firing;
+                    rule.state_since = Some(now);
+                    rule.last_fired_at = Some(now);
+                    format!(
+                        "{}: open the window in {}. {}",
+                        rule.name, recommendation.label, recommendation.reason
+                    )
+                }
+                AlertEventKind::Resolved => {
+                    rule.state = AlertState::Ok;
+                    rule.state_since = None;
+                    format!(
+                        "{}: {} no longer needs airing. {}",
+                        rule.name, recommendation.label, recommendation.reason
+                    )
+                }
+            };
+            events.push(
+                alert::create_event(
+                    &mut tx,
+                    &rule,
+                    kind,
+                    recommendation.difference,
+                    &message,
+                    now,
+                )
+                .await?,
+            );
+        }
+        alert::save_state(&mut tx, &rule).await?;
+    }
+
+    notify::enqueue_events(&mut tx, &events).await?;
+    tx.commit().await?;
+    if !events.is_empty() {
+        notify::wake();
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::data_entry::{ABSOLUTE_HUMIDITY, HUMIDITY, Metric, TEMPERATURE};
+
+    fn now() -> DateTime<Utc> {
+        "2025-07-01T12:00:00Z".parse().unwrap()
+    }
+
+    fn settings() -> VentilationSettings {
+        VentilationSettings {
+            target_humidity: 60.0,
+            min_difference: 1.0,
+            forecast_hours: 6,
+        }
+    }
+
+    fn room(humidity: f64, absolute_humidity: f64) -> LatestReading {
+        let metrics = [
+            (TEMPERATURE, 21.0),
+            (HUMIDITY, humidity),
+            (ABSOLUTE_HUMIDITY, absolute_humidity),
+        ];
+        LatestReading {
+            mapping_id: 1,
+            unique_identifier: "AA".to_string(),
+            label: "Kitchen".to_string(),
+            created_at: Some(now()),
+            age_seconds: Some(0),
+            status: SensorStatus::Online,
+            metrics: metrics
+                .iter()
+                .map(|&(name, value)| Metric {
+                    name: name.to_string(),
+                    value,
+                    unit: None,
+                })
+                .collect(),
+            condensation_risk: None,
+        }
+    }
+
+    fn outdoor(absolute_humidity: f64) -> Climate {
+        Climate {
+            time: now(),
+            temperature: 15.0,
+            humidity: 70.0,
+            absolute_humidity,
+        }
+    }
+
+    fn hour(hours: i64, absolute_humidity: f64) -> ForecastHour {
+        ForecastHour {
+            time: now() + Duration::hours(hours),
+            temperature: 15.0,
+            humidity: 70.0,
+            dew_point: 10.0,
+            absolute_humidity,
+        }
+    }
+
+    #[test]
+    fn unknown_without_indoor_or_outdoor_climate() {
+        let mut offline = room(70.0, 12.0);
+        offline.status = SensorStatus::Offline;
+        let mut without_humidity = room(70.0, 12.0);
+        without_humidity
+            .metrics
+            .retain(|metric| metric.name != HUMIDITY);
+        for room in [offline, without_humidity] {
+            let recommendation = recommend(&room, Some(&outdoor(8.0)), &[], &settings(), Tz::UTC);
+            assert_eq!(recommendation.advice, VentilationAdvice::Unknown);
+            assert_eq!(
+                recommendation.reason,
+                "No recent indoor reading with temperature and humidity"
+            );
+            assert!(recommendation.indoor.is_none());
+        }
+
+        let recommendation = recommend(&room(70.0, 12.0), None, &[], &settings(), Tz::UTC);
+        assert_eq!(recommendation.advice, VentilationAdvice::Unknown);
+        assert_eq!(
+            recommendation.reason,
+            "No recent outdoor weather, set a location to get it"
+        );
+        assert_eq!(recommendation.difference, None);
+    }
+
+    #[test]
+    fn keeps_closed_when_humidity_is_fine() {
+        let recommendation = recommend(
+            &room(50.0, 9.0),
+            Some(&outdoor(5.0)),
+            &[],
+            &settings(),
+            Tz::UTC,
+        );
+        assert_eq!(recommendation.advice, VentilationAdvice::KeepClosed);
+        assert_eq!(
+            recommendation.reason,
+            "Humidity inside is 50%, below 60%, so there is no need to air out"
+        );
+        assert_eq!(recommendation.difference, Some(4.0));
+    }
+
+    #[test]
+    fn keeps_closed_when_outdoor_air_is_not_drier() {
+        let forecast = [hour(1, 11.5), hour(2, 12.5)];
+        let recommendation = recommend(
+            &room(70.0, 12.0),
+            Some(&outdoor(11.5)),
+            &forecast,
+            &settings(),
+            Tz::UTC,
+        );
+        assert_eq!(recommendation.advice, VentilationAdvice::KeepClosed);
+        assert_eq!(
+            recommendation.reason,
+            "Outdoor air holds 11.5 g/m³ of water against 12 g/m³ inside, so airing out would not dry the room"
+        );
+        assert_eq!(recommendation.difference, Some(0.5));
+        assert_eq!(recommendation.best_time, None);
+    }
+
+    #[test]
+    fn opens_when_outdoor_air_is_drier() {
+        let recommendation = recommend(
+            &room(70.0, 12.0),
+            Some(&outdoor(11.0)),
+            &[hour(1, 10.0)],
+            &settings(),
+            Tz::UTC,
+        );
+        assert_eq!(recommendation.advice, VentilationAdvice::Open);
+        assert_eq!(
+            recommendation.reason,
+            "Outdoor air holds 11 g/m³ of water against 12 g/m³ inside, so airing out dries the room"
+        );
+        assert_eq!(recommendation.difference, Some(1.0));
+    }
+
+    #[test]
+    fn opens_until_it_gets_more_humid_in_the_users_timezone() {
+        let forecast = [hour(1, 9.0), hour(3, 11.5), hour(4, 13.0)];
+        let recommendation = recommend(
+            &room(70.0, 12.0),
+            Some(&outdoor(8.0)),
+            &forecast,
+            &settings(),
+            Tz::UTC,
+        );
+        assert_eq!(recommendation.advice, VentilationAdvice::Open);
+        assert!(
+            recommendation
+                .reason
+                .ends_with(", best before 15:00 UTC when it gets more humid outside"),
+            "{}",
+            recommendation.reason
+        );
+
+        let recommendation = recommend(
+            &room(70.0, 12.0),
+            Some(&outdoor(8.0)),
+            &forecast,
+            &settings(),
+            chrono_tz::Europe::Copenhagen,
+        );
+        assert!(
+            recommendation
+                .reason
+                .ends_with(", best before 17:00 CEST when it gets more humid outside"),
+            "{}",
+            recommendation.reason
+        );
+    }
+
+    #[test]
+    fn waits_for_the_driest_hour_ahead() {
+        let forecast = [hour(1, 11.8), hour(2, 10.5), hour(4, 9.0), hour(5, 9.5)];
+        let recommendation = recommend(
+            &room(70.0, 12.0),
+            Some(&outdoor(11.5)),
+            &forecast,
+            &settings(),
+            chrono_tz::Europe::Copenhagen,
+        );
+        assert_eq!(recommendation.advice, VentilationAdvice::Wait);
+        assert_eq!(recommendation.best_time, Some(now() + Duration::hours(4)));
+        assert_eq!(
+            recommendation.reason,
+            "Outdoor air holds 11.5 g/m³ of water against 12 g/m³ inside, but it is forecast to drop to 9 g/m³ at 18:00 CEST"
+        );
+    }
+}
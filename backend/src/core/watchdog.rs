@@ -0,0 +1,87 @@
+use chrono::Utc;
+use log::{error, info};
+use sqlx::{Pool, Postgres};
+
+use crate::core::config::env_or;
+use crate::core::notify;
+use crate::models::alert::{self, AlertEvent, AlertEventKind, AlertState};
+use crate::models::data_entry;
+
+// Fire offline rules on sensors that stopped reporting. Rules resolve again
+// when the next reading is ingested.
+pub fn start(db_pool: &Pool<Postgres>) {
+    let db = db_pool.clone();
+    let interval = std::time::Duration::from_secs(env_or("SENSOR_WATCHDOG_INTERVAL_SECS", 60));
+
+    tokio::spawn(async move {
+        loop {
+            match run(&db).await {
+                Ok(events) => {
+                    for event in &events {
+                        info!("Alert {:?}: {}", event.kind, event.message);
+                    }
+                }
+                Err(e) => error!("Sensor watchdog failed: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+async fn run(db: &Pool<Postgres>) -> Result<Vec<AlertEvent>, sqlx::Error> {
+    let now = Utc::now();
+    let mut tx = db.begin().await?;
+    let rules = alert::lock_offline_candidates(&mut tx).await?;
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut identifiers: Vec<String> = rules
+        .iter()
+        .map(|rule| rule.unique_identifier.clone())
+        .collect();
+    identifiers.sort();
+    identifiers.dedup();
+    let last_seen = data_entry::get_last_seen(db, &identifiers).await?;
+
+    let mut events = Vec::new();
+    for mut rule in rules {
+        let seen = last_seen
+            .get(&rule.unique_identifier)
+            .copied()
+            .max(rule.evaluated_at);
+        // A sensor that never reported counts as silent since the rule was created
+        if now - seen.unwrap_or(rule.created_at) < rule.offline_after() {
+            continue;
+        }
+
+        rule.state = AlertState::Firing;
+        rule.state_since = Some(now);
+        rule.last_fired_at = Some(now);
+        rule.evaluated_at = seen;
+        let message = match seen {
+            Some(seen) => format!(
+                "{}: {} has not reported since {}",
+                rule.name,
+                rule.unique_identifier,
+                seen.format("%Y-%m-%d %H:%M UTC")
+            ),
+            None => format!(
+                "{}: {} has never reported",
+                rule.name, rule.unique_identifier
+            ),
+        };
+        events.push(
+            alert::create_event(&mut tx, &rule, AlertEventKind::Fired, None, &message, now).await?,
+        );
+        alert::save_state(&mut tx, &rule).await?;
+    }
+
+    notify::enqueue_events(&mut tx, &events).await?;
+    tx.commit().await?;
+    if !events.is_empty() {
+        notify::wake();
+    }
+
+    Ok(events)
+}
@@ -0,0 +1,297 @@
+use chrono::{NaiveDateTime, Utc};
+use futures_util::future::BoxFuture;
+use log::{error, info, warn};
+use serde::Deserialize;
+use sqlx::{Pool, Postgres};
+use std::sync::OnceLock;
+use tokio::sync::Notify;
+
+use crate::core::config::env_or;
+use crate::core::ingest;
+use crate::core::psychrometrics::{absolute_humidity, dew_point};
+use crate::core::stream::LiveReadings;
+use crate::models::data_entry::{
+    self, HUMIDITY, Metric, NewDataEntry, Round, TEMPERATURE, default_unit,
+};
+use crate::models::weather::{self, ForecastHour, WeatherHour, WeatherLocation};
+
+// Wakes the job when a user sets a location, so it does not wait for the next round
+static LOCATION_SET: Notify = Notify::const_new();
+
+// Source of outdoor temperature and humidity
+pub trait WeatherProvider: Send + Sync {
+    // Hourly weather at a place, from `past_days` ago until `forecast_days` ahead
+    fn fetch<'a>(
+        &'a self,
+        location: &'a WeatherLocation,
+        past_days: i64,
+        forecast_days: i64,
+    ) -> BoxFuture<'a, Result<Vec<WeatherHour>, String>>;
+}
+
+// Hourly series in the shape Open-Meteo returns them
+#[derive(Deserialize)]
+struct OpenMeteoResponse {
+    hourly: OpenMeteoHourly,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoHourly {
+    // UTC, e.g. "2025-04-15T13:00"
+    time: Vec<String>,
+    temperature_2m: Vec<Option<f64>>,
+    relative_humidity_2m: Vec<Option<f64>>,
+}
+
+impl OpenMeteoResponse {
+    // Hours missing a value are left out
+    fn into_hours(self) -> Result<Vec<WeatherHour>, String> {
+        let hourly = self.hourly;
+        let mut hours = Vec::with_capacity(hourly.time.len());
+        for (index, time) in hourly.time.iter().enumerate() {
+            let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M")
+                .map_err(|e| format!("Invalid time {:?}: {}", time, e))?
+                .and_utc();
+            let temperature = hourly.temperature_2m.get(index).copied().flatten();
+            let humidity = hourly.relative_humidity_2m.get(index).copied().flatten();
+            if let (Some(temperature), Some(humidity)) = (temperature, humidity) {
+                hours.push(WeatherHour {
+                    time,
+                    temperature,
+                    humidity,
+                });
+            }
+        }
+        Ok(hours)
+    }
+}
+
+// Forecasts and recent history from the Open-Meteo API, which needs no key
+pub struct OpenMeteo {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl OpenMeteo {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(30))
+                .build()
+                .expect("Failed to build HTTP client"),
+            url,
+        }
+    }
+}
+
+impl WeatherProvider for OpenMeteo {
+    fn fetch<'a>(
+        &'a self,
+        location: &'a WeatherLocation,
+        past_days: i64,
+        forecast_days: i64,
+    ) -> BoxFuture<'a, Result<Vec<WeatherHour>, String>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .get(&self.url)
+                .query(&[
+                    ("latitude", location.latitude.to_string()),
+                    ("longitude", location.longitude.to_string()),
+                    ("hourly", "temperature_2m,relative_humidity_2m".to_string()),
+                    // The API allows up to 92 days back and 16 ahead
+                    ("past_days", past_days.clamp(0, 92).to_string()),
+                    ("forecast_days", forecast_days.clamp(1, 16).to_string()),
+                    ("timezone", "GMT".to_string()),
+                ])
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            let status = response.status();
+            if !status.is_success() {
+                let body = response.text().await.unwrap_or_default();
+                return Err(format!(
+                    "{}: {}",
+                    status,
+                    body.chars().take(200).collect::<String>()
+                ));
+            }
+            response
+                .json::<OpenMeteoResponse>()
+                .await
+                .map_err(|e| e.to_string())?
+                .into_hours()
+        })
+    }
+}
+
+// Weather read from a file in the Open-Meteo format, for every location alike.
+// Meant for testing and offline setups, so the whole file is returned each time.
+pub struct WeatherFile {
+    path: String,
+}
+
+impl WeatherFile {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+}
+
+impl WeatherProvider for WeatherFile {
+    fn fetch<'a>(
+        &'a self,
+        _location: &'a WeatherLocation,
+        _past_days: i64,
+        _forecast_days: i64,
+    ) -> BoxFuture<'a, Result<Vec<WeatherHour>, String>> {
+        Box::pin(async move {
+            let contents = tokio::fs::read_to_string(&self.path)
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", self.path, e))?;
+            serde_json::from_str::<OpenMeteoResponse>(&contents)
+                .map_err(|e| format!("Invalid weather file {}: {}", self.path, e))?
+                .into_hours()
+        })
+    }
+}
+
+// The provider picked with WEATHER_PROVIDER, None when weather is turned off
+pub fn provider() -> Option<&'static dyn WeatherProvider> {
+    static PROVIDER: OnceLock<Option<Box<dyn WeatherProvider>>> = OnceLock::new();
+    PROVIDER
+        .get_or_init(
+            || match env_or("WEATHER_PROVIDER", "open-meteo".to_string()).as_str() {
+                "open-meteo" => Some(Box::new(OpenMeteo::new(env_or(
+                    "WEATHER_OPEN_METEO_URL",
+                    "https://api.open-meteo.com/v1/forecast".to_string(),
+                ))) as Box<dyn WeatherProvider>),
+                "file" => match std::env::var("WEATHER_FILE") {
+                    Ok(path) => Some(Box::new(WeatherFile::new(path)) as Box<dyn WeatherProvider>),
+                    Err(_) => {
+                        warn!("WEATHER_PROVIDER is file but WEATHER_FILE is not set");
+                        None
+                    }
+                },
+                "none" => None,
+                other => {
+                    warn!(
+                        "Unknown WEATHER_PROVIDER {:?}, weather is turned off",
+                        other
+                    );
+                    None
+                }
+            },
+        )
+        .as_deref()
+}
+
+// Fetch weather for a location that was just set without waiting for the next round
+pub fn location_set() {
+    LOCATION_SET.notify_one();
+}
+
+// Add dew point and absolute humidity to forecast hours
+pub fn forecast_hours(hours: Vec<WeatherHour>) -> Vec<ForecastHour> {
+    hours
+        .into_iter()
+        .map(|hour| ForecastHour {
+            time: hour.time,
+            temperature: hour.temperature,
+            humidity: hour.humidity,
+            dew_point: dew_point(hour.temperature, hour.humidity).to_2_decimal(),
+            absolute_humidity: absolute_humidity(hour.temperature, hour.humidity).to_2_decimal(),
+        })
+        .collect()
+}
+
+// Fetch weather for every location someone is at in the background
+pub fn start(db_pool: &Pool<Postgres>, live: &LiveReadings) {
+    let Some(provider) = provider() else {
+        info!("Weather is turned off");
+        return;
+    };
+    let db = db_pool.clone();
+    let live = live.clone();
+    let interval = std::time::Duration::from_secs(env_or("WEATHER_INTERVAL_SECS", 1800));
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = run(&db, &live, provider, interval).await {
+                error!("Weather update failed: {}", e);
+            }
+            let _ = tokio::time::timeout(interval, LOCATION_SET.notified()).await;
+        }
+    });
+}
+
+// Fetch the locations that are due. Past hours are stored as readings of the
+// virtual sensor, the hours to come as its forecast.
+async fn run(
+    db: &Pool<Postgres>,
+    live: &LiveReadings,
+    provider: &dyn WeatherProvider,
+    interval: std::time::Duration,
+) -> Result<(), sqlx::Error> {
+    let backfill_days: i64 = env_or("WEATHER_BACKFILL_DAYS", 7);
+    let forecast_days: i64 = env_or("WEATHER_FORECAST_DAYS", 2);
+    // Leave some slack, so a location fetched late in the last round is not skipped
+    let due = weather::get_due(db, interval.as_secs_f64() * 0.9).await?;
+    if due.is_empty() {
+        return Ok(());
+    }
+
+    let identifiers: Vec<String> = due
+        .iter()
+        .map(|location| location.unique_identifier.clone())
+        .collect();
+    let last_seen = data_entry::get_last_seen(db, &identifiers).await?;
+
+    for location in due {
+        let now = Utc::now();
+        // Only go back as far as the readings stored so far
+        let past_days = match last_seen.get(&location.unique_identifier) {
+            Some(seen) => ((now - *seen).num_hours() / 24 + 1).min(backfill_days),
+            None => backfill_days,
+        };
+
+        let hours = match provider.fetch(&location, past_days, forecast_days).await {
+            Ok(hours) => hours,
+            Err(e) => {
+                warn!(
+                    "Fetching weather for {} failed: {}",
+                    location.unique_identifier, e
+                );
+                weather::save_error(db, &location.unique_identifier, &e, now).await?;
+                continue;
+            }
+        };
+
+        let (past, forecast): (Vec<WeatherHour>, Vec<WeatherHour>) =
+            hours.into_iter().partition(|hour| hour.time <= now);
+        let entries: Vec<NewDataEntry> = past
+            .into_iter()
+            .map(|hour| NewDataEntry {
+                unique_identifier: location.unique_identifier.clone(),
+                created_at: hour.time,
+                topic: None,
+                metrics: vec![
+                    Metric {
+                        name: HUMIDITY.to_string(),
+                        value: hour.humidity,
+                        unit: default_unit(HUMIDITY),
+                    },
+                    Metric {
+                        name: TEMPERATURE.to_string(),
+                        value: hour.temperature,
+                        unit: default_unit(TEMPERATURE),
+                    },
+                ],
+            })
+            .collect();
+        // Hours stored in an earlier round come back as duplicates and are skipped
+        ingest::store_entries(db, live, entries).await?;
+        weather::save_forecast(db, &location.unique_identifier, &forecast, now).await?;
+    }
+
+    Ok(())
+}
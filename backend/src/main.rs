@@ -1,5 +1,6 @@
-use log::{error, info};
+use log::{info, warn};
 
+mod cli;
 mod core;
 mod models;
 mod routes;
@@ -18,14 +19,34 @@ async fn main() {
     let database_pool = core::database::establish_connection().await;
     let _ = sqlx::migrate!().run(&database_pool).await;
 
-    let _connection = core::message_queue::create_consume_thread(&database_pool)
-        .map_err(|e| {
-            error!("Failed to create RabbitMQ consumer: {}", e);
-            e
-        })
-        .unwrap();
+    // Admin commands, e.g. `backend device add <identifier>`
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&database_pool, &args).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if !core::ingest::device_auth_required() {
+        warn!("DEVICE_AUTH_REQUIRED is disabled, readings of unregistered devices are accepted");
+    }
+
+    let live = core::stream::channel();
+    let consumer_status = core::message_queue::start_consumer(&database_pool, &live);
+    core::rollup::start(&database_pool);
+    core::notify::start(&database_pool);
+    core::watchdog::start(&database_pool);
+    core::mold::start(&database_pool);
+    core::weather::start(&database_pool, &live);
+    core::ventilation::start(&database_pool);
 
-    let state = routes::AppState { db: database_pool };
+    let state = routes::AppState {
+        db: database_pool,
+        consumer_status,
+        live,
+    };
     let app = routes::create_router(state);
 
     info!("Starting HTTP server on 0.0.0.0:3000");
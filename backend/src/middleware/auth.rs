@@ -9,6 +9,9 @@ use axum::{
 use crate::models::app_user::validate_token;
 use crate::routes::AppState;
 
+// The only route browsers open without being able to send headers
+const STREAM_PATH: &str = "/stream";
+
 // Extract and validate JWT token from request headers
 pub async fn auth_middleware(
     _state: State<AppState>,
@@ -16,23 +19,28 @@ pub async fn auth_middleware(
     next: Next,
 ) -> Result<Response, StatusCode> {
     // Extract the token from the Authorization header
-    let auth_header = request
-        .headers()
-        .get(AUTHORIZATION)
-        .ok_or(StatusCode::UNAUTHORIZED)?
-        .to_str()
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
-    
-    // Check that it's a Bearer token
-    if !auth_header.starts_with("Bearer ") {
-        return Err(StatusCode::UNAUTHORIZED);
-    }
-    
-    // Extract the token itself
-    let token = &auth_header[7..]; // Skip "Bearer " prefix
+    let token = match request.headers().get(AUTHORIZATION) {
+        Some(auth_header) => {
+            let auth_header = auth_header.to_str().map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+            // Check that it's a Bearer token
+            if !auth_header.starts_with("Bearer ") {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+
+            // Extract the token itself
+            auth_header[7..].to_string() // Skip "Bearer " prefix
+        }
+        // Browsers cannot set headers on EventSource and WebSocket connections.
+        // Only /stream takes the token from the URL, so it stays out of logs elsewhere.
+        None if request.uri().path() == STREAM_PATH => {
+            query_token(&request).ok_or(StatusCode::UNAUTHORIZED)?
+        }
+        None => return Err(StatusCode::UNAUTHORIZED),
+    };
     
     // Validate the token
-    match validate_token(token) {
+    match validate_token(&token) {
         Ok(claims) => {
             // Add the claims to the request extensions for later use
             request.extensions_mut().insert(claims);
@@ -42,3 +50,11 @@ pub async fn auth_middleware(
         Err(_) => Err(StatusCode::UNAUTHORIZED),
     }
 }
+
+// Read the token from the `access_token` query parameter
+fn query_token(request: &Request<Body>) -> Option<String> {
+    request.uri().query()?.split('&').find_map(|pair| {
+        pair.strip_prefix("access_token=")
+            .map(|token| token.to_string())
+    })
+}
@@ -0,0 +1,512 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Transaction};
+
+use crate::core::ventilation::VentilationSettings;
+
+// When a threshold rule counts as breached
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum AlertCondition {
+    Above,
+    Below,
+    // No readings for longer than the rule's duration
+    Offline,
+    // Up by at least the threshold from the lowest value within the window
+    Rises,
+    // Down by at least the threshold from the highest value within the window
+    Falls,
+    // Airing out would help, with outdoor air drier by at least the threshold in g/m³
+    Ventilate,
+}
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum AlertState {
+    Ok,
+    // Breached, but not for long enough yet
+    Pending,
+    Firing,
+}
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum AlertEventKind {
+    Fired,
+    Resolved,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AlertRule {
+    pub id: i32,
+    pub user_id: i32,
+    pub unique_identifier: String,
+    pub name: String,
+    pub metric: String,
+    pub condition: AlertCondition,
+    pub threshold: f64,
+    // How far the value has to move back past the threshold to resolve the alert
+    pub hysteresis: f64,
+    // How long the threshold has to be breached before the alert fires
+    pub duration_seconds: i32,
+    // Sliding window of rate-of-change rules
+    pub window_seconds: i32,
+    // How long after firing the rule cannot fire again
+    pub cooldown_seconds: i32,
+    pub enabled: bool,
+    pub state: AlertState,
+    pub state_since: Option<DateTime<Utc>>,
+    // Newest value evaluated, the change within the window for rate-of-change rules
+    pub last_value: Option<f64>,
+    pub evaluated_at: Option<DateTime<Utc>>,
+    pub last_fired_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateAlertRule {
+    pub unique_identifier: String,
+    // Defaults to a description of the condition, e.g. "humidity above 70"
+    pub name: Option<String>,
+    // Defaults to humidity
+    pub metric: Option<String>,
+    pub condition: AlertCondition,
+    // Not used by offline rules. Defaults to VENTILATION_MIN_DIFFERENCE for
+    // ventilation rules.
+    pub threshold: Option<f64>,
+    pub hysteresis: Option<f64>,
+    // For offline rules, how long the sensor may stay silent. Defaults to
+    // SENSOR_OFFLINE_AFTER_INTERVALS.
+    pub duration_seconds: Option<i32>,
+    // Required for rate-of-change rules
+    pub window_seconds: Option<i32>,
+    pub cooldown_seconds: Option<i32>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateAlertRule {
+    pub name: Option<String>,
+    pub metric: Option<String>,
+    pub condition: Option<AlertCondition>,
+    pub threshold: Option<f64>,
+    pub hysteresis: Option<f64>,
+    pub duration_seconds: Option<i32>,
+    pub window_seconds: Option<i32>,
+    pub cooldown_seconds: Option<i32>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AlertEvent {
+    pub id: i32,
+    pub rule_id: i32,
+    pub rule_name: String,
+    pub unique_identifier: String,
+    pub kind: AlertEventKind,
+    pub value: Option<f64>,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct AlertHistoryQuery {
+    pub rule_id: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+// Create a new alert rule
+pub async fn create(
+    db: &Pool<Postgres>,
+    rule: CreateAlertRule,
+    user_id: i32,
+) -> Result<AlertRule, sqlx::Error> {
+    let metric = rule
+        .metric
+        .map(|metric| metric.trim().to_lowercase())
+        .unwrap_or_else(|| "humidity".to_string());
+    let threshold = rule.threshold.unwrap_or(match rule.condition {
+        AlertCondition::Ventilate => VentilationSettings::from_env().min_difference,
+        _ => 0.0,
+    });
+    let name = rule.name.unwrap_or_else(|| match rule.condition {
+        AlertCondition::Above => format!("{} above {}", metric, threshold),
+        AlertCondition::Below => format!("{} below {}", metric, threshold),
+        AlertCondition::Offline => format!("{} offline", rule.unique_identifier),
+        AlertCondition::Rises => format!("{} rises by {}", metric, threshold),
+        AlertCondition::Falls => format!("{} falls by {}", metric, threshold),
+        AlertCondition::Ventilate => format!("{} ventilation", rule.unique_identifier),
+    });
+
+    let result = sqlx::query_as!(
+        AlertRule,
+        r#"
+        INSERT INTO alert_rule
+            (user_id, unique_identifier, name, metric, condition, threshold, hysteresis,
+            duration_seconds, window_seconds, cooldown_seconds, enabled)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        RETURNING id, user_id, unique_identifier, name, metric,
+            condition as "condition: AlertCondition", threshold, hysteresis, duration_seconds,
+            window_seconds, cooldown_seconds, enabled, state as "state: AlertState", state_since,
+            last_value, evaluated_at, last_fired_at, created_at
+        "#,
+        user_id,
+        rule.unique_identifier,
+        name,
+        metric,
+        rule.condition as _,
+        threshold,
+        rule.hysteresis.unwrap_or(0.0),
+        rule.duration_seconds.unwrap_or(0),
+        rule.window_seconds.unwrap_or(0),
+        rule.cooldown_seconds.unwrap_or(0),
+        rule.enabled.unwrap_or(true)
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(result)
+}
+
+// Get all alert rules of a user
+pub async fn get_all_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+) -> Result<Vec<AlertRule>, sqlx::Error> {
+    let rules = sqlx::query_as!(
+        AlertRule,
+        r#"
+        SELECT id, user_id, unique_identifier, name, metric,
+            condition as "condition: AlertCondition", threshold, hysteresis, duration_seconds,
+            window_seconds, cooldown_seconds, enabled, state as "state: AlertState", state_since,
+            last_value, evaluated_at, last_fired_at, created_at
+        FROM alert_rule
+        WHERE user_id = $1
+        ORDER BY id
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rules)
+}
+
+// Get the rules of a user that are currently firing
+pub async fn get_firing_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+) -> Result<Vec<AlertRule>, sqlx::Error> {
+    let rules = sqlx::query_as!(
+        AlertRule,
+        r#"
+        SELECT id, user_id, unique_identifier, name, metric,
+            condition as "condition: AlertCondition", threshold, hysteresis, duration_seconds,
+            window_seconds, cooldown_seconds, enabled, state as "state: AlertState", state_since,
+            last_value, evaluated_at, last_fired_at, created_at
+        FROM alert_rule
+        WHERE user_id = $1 AND state = 'firing'
+        ORDER BY state_since
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rules)
+}
+
+// Get a single alert rule by ID and verify user ownership
+pub async fn get_by_id_for_user(
+    db: &Pool<Postgres>,
+    id: i32,
+    user_id: i32,
+) -> Result<Option<AlertRule>, sqlx::Error> {
+    let rule = sqlx::query_as!(
+        AlertRule,
+        r#"
+        SELECT id, user_id, unique_identifier, name, metric,
+            condition as "condition: AlertCondition", threshold, hysteresis, duration_seconds,
+            window_seconds, cooldown_seconds, enabled, state as "state: AlertState", state_since,
+            last_value, evaluated_at, last_fired_at, created_at
+        FROM alert_rule
+        WHERE id = $1 AND user_id = $2
+        "#,
+        id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(rule)
+}
+
+// Update an alert rule. Its evaluation starts over, as the old state may not
+// match the new condition.
+pub async fn update(
+    db: &Pool<Postgres>,
+    id: i32,
+    rule: UpdateAlertRule,
+    user_id: i32,
+) -> Result<Option<AlertRule>, sqlx::Error> {
+    let Some(existing) = get_by_id_for_user(db, id, user_id).await? else {
+        return Ok(None);
+    };
+
+    let updated = sqlx::query_as!(
+        AlertRule,
+        r#"
+        UPDATE alert_rule
+        SET
+            name = $1,
+            metric = $2,
+            condition = $3,
+            threshold = $4,
+            hysteresis = $5,
+            duration_seconds = $6,
+            window_seconds = $7,
+            cooldown_seconds = $8,
+            enabled = $9,
+            state = 'ok',
+            state_since = NULL,
+            evaluated_at = NULL
+        WHERE id = $10 AND user_id = $11
+        RETURNING id, user_id, unique_identifier, name, metric,
+            condition as "condition: AlertCondition", threshold, hysteresis, duration_seconds,
+            window_seconds, cooldown_seconds, enabled, state as "state: AlertState", state_since,
+            last_value, evaluated_at, last_fired_at, created_at
+        "#,
+        rule.name.unwrap_or(existing.name),
+        rule.metric
+            .map(|metric| metric.trim().to_lowercase())
+            .unwrap_or(existing.metric),
+        rule.condition.unwrap_or(existing.condition) as _,
+        rule.threshold.unwrap_or(existing.threshold),
+        rule.hysteresis.unwrap_or(existing.hysteresis),
+        rule.duration_seconds.unwrap_or(existing.duration_seconds),
+        rule.window_seconds.unwrap_or(existing.window_seconds),
+        rule.cooldown_seconds.unwrap_or(existing.cooldown_seconds),
+        rule.enabled.unwrap_or(existing.enabled),
+        id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(updated)
+}
+
+// Delete an alert rule and its history
+pub async fn delete(db: &Pool<Postgres>, id: i32, user_id: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM alert_rule WHERE id = $1 AND user_id = $2",
+        id,
+        user_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Get the newest alert events of a user's rules
+pub async fn get_history_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    query: &AlertHistoryQuery,
+) -> Result<Vec<AlertEvent>, sqlx::Error> {
+    let events = sqlx::query_as!(
+        AlertEvent,
+        r#"
+        SELECT ae.id, ae.rule_id, ar.name as rule_name, ar.unique_identifier,
+            ae.kind as "kind: AlertEventKind", ae.value, ae.message, ae.created_at
+        FROM alert_event ae
+        JOIN alert_rule ar ON ar.id = ae.rule_id
+        WHERE ar.user_id = $1
+        AND ($2::int4 IS NULL OR ae.rule_id = $2)
+        ORDER BY ae.created_at DESC, ae.id DESC
+        LIMIT $3
+        "#,
+        user_id,
+        query.rule_id,
+        query.limit.unwrap_or(100)
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(events)
+}
+
+// Lock the enabled rules on the given identifiers for evaluation. Rules of users
+// who no longer have the sensor mapped are left out.
+pub async fn lock_enabled_for_identifiers(
+    tx: &mut Transaction<'_, Postgres>,
+    unique_identifiers: &[String],
+) -> Result<Vec<AlertRule>, sqlx::Error> {
+    let rules = sqlx::query_as!(
+        AlertRule,
+        r#"
+        SELECT id, user_id, unique_identifier, name, metric,
+            condition as "condition: AlertCondition", threshold, hysteresis, duration_seconds,
+            window_seconds, cooldown_seconds, enabled, state as "state: AlertState", state_since,
+            last_value, evaluated_at, last_fired_at, created_at
+        FROM alert_rule
+        WHERE enabled AND unique_identifier = ANY($1)
+        AND EXISTS (
+            SELECT 1 FROM data_entry_mapping dem
+            WHERE dem.unique_identifier = alert_rule.unique_identifier
+            AND dem.user_id = alert_rule.user_id
+        )
+        ORDER BY id
+        FOR UPDATE
+        "#,
+        unique_identifiers
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(rules)
+}
+
+// Lock the enabled offline rules that have not fired yet, for the watchdog
+pub async fn lock_offline_candidates(
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<AlertRule>, sqlx::Error> {
+    let rules = sqlx::query_as!(
+        AlertRule,
+        r#"
+        SELECT id, user_id, unique_identifier, name, metric,
+            condition as "condition: AlertCondition", threshold, hysteresis, duration_seconds,
+            window_seconds, cooldown_seconds, enabled, state as "state: AlertState", state_since,
+            last_value, evaluated_at, last_fired_at, created_at
+        FROM alert_rule
+        WHERE enabled AND condition = 'offline' AND state <> 'firing'
+        AND EXISTS (
+            SELECT 1 FROM data_entry_mapping dem
+            WHERE dem.unique_identifier = alert_rule.unique_identifier
+            AND dem.user_id = alert_rule.user_id
+        )
+        ORDER BY id
+        FOR UPDATE SKIP LOCKED
+        "#
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(rules)
+}
+
+// Lock the enabled ventilation rules, which are checked against the weather
+// rather than on readings
+pub async fn lock_ventilation_rules(
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<AlertRule>, sqlx::Error> {
+    let rules = sqlx::query_as!(
+        AlertRule,
+        r#"
+        SELECT id, user_id, unique_identifier, name, metric,
+            condition as "condition: AlertCondition", threshold, hysteresis, duration_seconds,
+            window_seconds, cooldown_seconds, enabled, state as "state: AlertState", state_since,
+            last_value, evaluated_at, last_fired_at, created_at
+        FROM alert_rule
+        WHERE enabled AND condition = 'ventilate'
+        AND EXISTS (
+            SELECT 1 FROM data_entry_mapping dem
+            WHERE dem.unique_identifier = alert_rule.unique_identifier
+            AND dem.user_id = alert_rule.user_id
+        )
+        ORDER BY user_id, id
+        FOR UPDATE SKIP LOCKED
+        "#
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(rules)
+}
+
+// Delete the rules on a sensor of users who no longer have it mapped, e.g. after
+// it was claimed or handed over, so they stop seeing its values
+pub async fn delete_unmapped(
+    tx: &mut Transaction<'_, Postgres>,
+    unique_identifier: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM alert_rule ar
+        WHERE ar.unique_identifier = $1
+        AND NOT EXISTS (
+            SELECT 1 FROM data_entry_mapping dem
+            WHERE dem.unique_identifier = ar.unique_identifier AND dem.user_id = ar.user_id
+        )
+        "#,
+        unique_identifier
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+// Store the evaluation state of a rule
+pub async fn save_state(
+    tx: &mut Transaction<'_, Postgres>,
+    rule: &AlertRule,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE alert_rule
+        SET state = $2, state_since = $3, last_value = $4, evaluated_at = $5, last_fired_at = $6
+        WHERE id = $1
+        "#,
+        rule.id,
+        rule.state as _,
+        rule.state_since,
+        rule.last_value,
+        rule.evaluated_at,
+        rule.last_fired_at
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+// Record that a rule fired or resolved
+pub async fn create_event(
+    tx: &mut Transaction<'_, Postgres>,
+    rule: &AlertRule,
+    kind: AlertEventKind,
+    value: Option<f64>,
+    message: &str,
+    created_at: DateTime<Utc>,
+) -> Result<AlertEvent, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO alert_event (rule_id, kind, value, message, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id
+        "#,
+        rule.id,
+        kind as _,
+        value,
+        message,
+        created_at
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    Ok(AlertEvent {
+        id: row.id,
+        rule_id: rule.id,
+        rule_name: rule.name.clone(),
+        unique_identifier: rule.unique_identifier.clone(),
+        kind,
+        value,
+        message: message.to_string(),
+        created_at,
+    })
+}
@@ -14,6 +14,8 @@ pub struct AppUser {
     pub username: String,
     #[serde(skip_serializing)]
     pub password: String,
+    // IANA timezone, e.g. "Europe/Copenhagen"
+    pub timezone: String,
     #[serde(skip_deserializing)]
     pub created_at: Option<DateTime<Utc>>,
 }
@@ -23,6 +25,7 @@ pub struct AppUser {
 pub struct CreateAppUser {
     pub username: String,
     pub password: String,
+    pub timezone: Option<String>,
 }
 
 // Struct for updating a user
@@ -30,6 +33,7 @@ pub struct CreateAppUser {
 pub struct UpdateAppUser {
     pub username: Option<String>,
     pub password: Option<String>,
+    pub timezone: Option<String>,
 }
 
 // Struct for user login
@@ -59,6 +63,7 @@ pub struct LoginResponse {
 pub struct UserResponse {
     pub id: i32,
     pub username: String,
+    pub timezone: String,
     pub created_at: Option<DateTime<Utc>>,
 }
 
@@ -67,6 +72,7 @@ impl From<AppUser> for UserResponse {
         Self {
             id: user.id,
             username: user.username,
+            timezone: user.timezone,
             created_at: user.created_at,
         }
     }
@@ -83,12 +89,13 @@ pub async fn create(
     let result = sqlx::query_as!(
         AppUser,
         r#"
-        INSERT INTO app_user (username, password)
-        VALUES ($1, $2)
-        RETURNING id, username, password, created_at
+        INSERT INTO app_user (username, password, timezone)
+        VALUES ($1, $2, $3)
+        RETURNING id, username, password, timezone, created_at
         "#,
         user.username,
-        hashed_password
+        hashed_password,
+        user.timezone.unwrap_or_else(|| "UTC".to_string())
     )
     .fetch_one(db)
     .await?;
@@ -101,7 +108,7 @@ pub async fn get_by_id(db: &Pool<Postgres>, id: i32) -> Result<Option<AppUser>,
     let user = sqlx::query_as!(
         AppUser,
         r#"
-        SELECT id, username, password, created_at
+        SELECT id, username, password, timezone, created_at
         FROM app_user
         WHERE id = $1
         "#,
@@ -118,7 +125,7 @@ pub async fn get_by_username(db: &Pool<Postgres>, username: &str) -> Result<Opti
     let user = sqlx::query_as!(
         AppUser,
         r#"
-        SELECT id, username, password, created_at
+        SELECT id, username, password, timezone, created_at
         FROM app_user
         WHERE username = $1
         "#,
@@ -146,6 +153,7 @@ pub async fn update(
             Some(pwd) => hash(pwd, DEFAULT_COST).unwrap(),
             None => existing.password,
         };
+        let timezone = user.timezone.unwrap_or(existing.timezone);
 
         let updated = sqlx::query_as!(
             AppUser,
@@ -153,12 +161,14 @@ pub async fn update(
             UPDATE app_user
             SET 
                 username = $1,
-                password = $2
-            WHERE id = $3
-            RETURNING id, username, password, created_at
+                password = $2,
+                timezone = $3
+            WHERE id = $4
+            RETURNING id, username, password, timezone, created_at
             "#,
             username,
             password,
+            timezone,
             id
         )
         .fetch_one(db)
@@ -170,7 +180,20 @@ pub async fn update(
     }
 }
 
+// Whether the database knows the IANA timezone name
+pub async fn is_valid_timezone(db: &Pool<Postgres>, timezone: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1) as "valid!""#,
+        timezone
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(result.valid)
+}
+
 // Validate login credentials and generate JWT token
+#[allow(clippy::collapsible_if)]
 pub async fn login(db: &Pool<Postgres>, credentials: LoginCredentials) -> Result<Option<LoginResponse>, sqlx::Error> {
     let user_result = get_by_username(db, &credentials.username).await?;
     
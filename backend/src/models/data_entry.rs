@@ -1,7 +1,13 @@
+use chrono::SubsecRound;
+use futures_util::stream::BoxStream;
 use serde::{Deserialize, Serialize};
 use sqlx::Pool;
 use sqlx::Postgres;
 
+use crate::core::psychrometrics::condensation_risk;
+use crate::models::mold::get_daily_max;
+use crate::models::rollup::get_tiers;
+
 #[derive(Serialize)]
 pub struct CountResponse {
     pub total_count: i64,
@@ -17,13 +23,31 @@ pub struct IdentifierCount {
     pub label: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Metric {
+    pub name: String,
+    pub value: f64,
+    pub unit: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
 pub struct DataEntry {
     pub id: i32,
     pub unique_identifier: String,
-    pub value: f64,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    pub metrics: Vec<Metric>,
+}
+
+// A reading that has not been stored yet
+pub struct NewDataEntry {
+    pub unique_identifier: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    // Routing key or MQTT topic the reading arrived on
+    pub topic: Option<String>,
+    pub metrics: Vec<Metric>,
 }
 
 #[derive(Deserialize)]
@@ -35,15 +59,139 @@ pub struct LimitQuery {
 pub struct AverageQuery {
     pub unique_identifiers: String,
     pub days: Option<i32>,
+    // IANA timezone overriding the user's own
+    pub tz: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct DailyAverage {
     pub date: chrono::NaiveDate,
+    // Humidity average, kept at the top level for existing clients
+    pub average_value: Option<f64>,
+    pub entry_count: i64,
+    pub metrics: Vec<MetricAverage>,
+    // Highest mold index of the day, None when it was not computed
+    pub mold_index: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct MetricAverage {
+    pub name: String,
+    pub unit: Option<String>,
     pub average_value: f64,
     pub entry_count: i64,
 }
 
+// Bucket size for time-range queries
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Bucket {
+    #[serde(rename = "raw")]
+    Raw,
+    #[serde(rename = "1m")]
+    Minute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+    #[serde(rename = "1w")]
+    Week,
+}
+
+impl Bucket {
+    // Length of the bucket in seconds, None for raw readings
+    pub fn seconds(self) -> Option<i64> {
+        match self {
+            Bucket::Raw => None,
+            Bucket::Minute => Some(60),
+            Bucket::FiveMinutes => Some(5 * 60),
+            Bucket::Hour => Some(60 * 60),
+            Bucket::Day => Some(24 * 60 * 60),
+            Bucket::Week => Some(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReadingsQuery {
+    pub unique_identifiers: String,
+    pub from: chrono::DateTime<chrono::Utc>,
+    // Defaults to now
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    // Defaults to raw readings
+    pub bucket: Option<Bucket>,
+    // Comma-separated metric names, all metrics when unset
+    pub metrics: Option<String>,
+    // IANA timezone overriding the user's own
+    pub tz: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ReadingBucket {
+    // Start of the bucket, or the reading time for raw readings
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub metrics: Vec<MetricStats>,
+    // Whether the highest dew point reached the surface temperature of the room,
+    // None when either is unknown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condensation_risk: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct MetricStats {
+    pub name: String,
+    pub unit: Option<String>,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct ReadingsResponse {
+    pub from: chrono::DateTime<chrono::Utc>,
+    pub to: chrono::DateTime<chrono::Utc>,
+    pub timezone: String,
+    #[serde(flatten)]
+    pub identifiers: std::collections::HashMap<String, Vec<ReadingBucket>>,
+    pub labels: std::collections::HashMap<String, String>,
+}
+
+// File format of an export
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    // Comma-separated identifiers, all mapped sensors when unset
+    pub unique_identifiers: Option<String>,
+    pub from: chrono::DateTime<chrono::Utc>,
+    // Defaults to now
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    // Defaults to CSV
+    pub format: Option<ExportFormat>,
+    // Comma-separated metric names, all metrics when unset
+    pub metrics: Option<String>,
+}
+
+// One metric of one reading, as written to an export
+#[derive(Serialize)]
+pub struct ExportRow {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub unique_identifier: String,
+    pub label: String,
+    pub metric: String,
+    pub value: f64,
+    pub unit: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct AverageResponse {
     #[serde(flatten)]
@@ -61,19 +209,145 @@ impl Round for f64 {
     }
 }
 
+pub const HUMIDITY: &str = "humidity";
+pub const TEMPERATURE: &str = "temperature";
+
+// Metrics derived from humidity and temperature when a reading is stored
+pub const DEW_POINT: &str = "dew_point";
+pub const ABSOLUTE_HUMIDITY: &str = "absolute_humidity";
+pub const HUMIDEX: &str = "humidex";
+
+// Unit used when a device reports a well-known metric without one
+pub fn default_unit(name: &str) -> Option<String> {
+    let unit = match name {
+        HUMIDITY => "%",
+        TEMPERATURE | DEW_POINT => "°C",
+        ABSOLUTE_HUMIDITY => "g/m³",
+        "pressure" => "kPa",
+        "gas_resistance" => "Ω",
+        _ => return None,
+    };
+    Some(unit.to_string())
+}
+
+// Store a batch of readings in a single transaction using multi-row inserts.
+// The result lines up with the input, with None for readings that already existed.
+pub async fn create_many(
+    db: &Pool<Postgres>,
+    entries: &[NewDataEntry],
+) -> Result<Vec<Option<(i32, chrono::DateTime<chrono::Utc>)>>, sqlx::Error> {
+    let identifiers: Vec<String> = entries.iter().map(|e| e.unique_identifier.clone()).collect();
+    // Postgres keeps microseconds, so finer times would not match the returned rows
+    let timestamps: Vec<chrono::DateTime<chrono::Utc>> =
+        entries.iter().map(|e| e.created_at.trunc_subsecs(6)).collect();
+    let topics: Vec<Option<String>> = entries.iter().map(|e| e.topic.clone()).collect();
+
+    let mut tx = db.begin().await?;
+
+    let inserted = sqlx::query!(
+        r#"
+            INSERT INTO data_entry (unique_identifier, created_at, topic)
+            SELECT * FROM UNNEST($1::varchar[], $2::timestamptz[], $3::varchar[])
+            ON CONFLICT (unique_identifier, created_at) DO NOTHING
+            RETURNING id, unique_identifier, created_at
+        "#,
+        &identifiers,
+        &timestamps,
+        &topics as &[Option<String>]
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut ids: std::collections::HashMap<(String, chrono::DateTime<chrono::Utc>), i32> = inserted
+        .into_iter()
+        .map(|row| ((row.unique_identifier, row.created_at), row.id))
+        .collect();
+
+    let mut created = Vec::with_capacity(entries.len());
+    let mut entry_ids: Vec<i32> = Vec::new();
+    let mut names: Vec<String> = Vec::new();
+    let mut values: Vec<f64> = Vec::new();
+    let mut units: Vec<Option<String>> = Vec::new();
+
+    for (entry, created_at) in entries.iter().zip(timestamps) {
+        // Removing the key also skips a second copy of the same reading within the batch
+        let Some(id) = ids.remove(&(entry.unique_identifier.clone(), created_at)) else {
+            created.push(None);
+            continue;
+        };
+
+        for metric in &entry.metrics {
+            entry_ids.push(id);
+            names.push(metric.name.clone());
+            values.push(metric.value);
+            units.push(metric.unit.clone());
+        }
+        created.push(Some((id, created_at)));
+    }
+
+    sqlx::query!(
+        r#"
+            INSERT INTO data_entry_metric (data_entry_id, name, value, unit)
+            SELECT * FROM UNNEST($1::int4[], $2::varchar[], $3::float8[], $4::varchar[])
+        "#,
+        &entry_ids,
+        &names,
+        &values,
+        &units as &[Option<String>]
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(created)
+}
+
+// Load the metrics of the given readings, keyed by reading id
+pub async fn get_metrics_for_entries(
+    db: &Pool<Postgres>,
+    entry_ids: &[i32],
+) -> std::collections::HashMap<i32, Vec<Metric>> {
+    let mut metrics_map: std::collections::HashMap<i32, Vec<Metric>> =
+        std::collections::HashMap::new();
+
+    let rows = sqlx::query!(
+        r#"
+            SELECT data_entry_id, name, value, unit
+            FROM data_entry_metric
+            WHERE data_entry_id = ANY($1)
+            ORDER BY name
+        "#,
+        entry_ids
+    )
+    .fetch_all(db)
+    .await
+    .unwrap();
+
+    for row in rows {
+        metrics_map.entry(row.data_entry_id).or_default().push(Metric {
+            name: row.name,
+            value: row.value.to_2_decimal(),
+            unit: row.unit,
+        });
+    }
+
+    metrics_map
+}
+
 // Get recent entries for a user
 pub async fn get_recent_entries_for_user(
     db: &Pool<Postgres>,
     user_id: i32,
     limit: i64,
 ) -> Vec<DataEntry> {
-    sqlx::query!(
+    let rows = sqlx::query!(
         r#"
             SELECT 
                 de.id, 
                 de.unique_identifier, 
-                de.value, 
                 de.created_at,
+                de.topic,
                 dem.label as "label?"
             FROM data_entry de
             JOIN data_entry_mapping dem ON de.unique_identifier = dem.unique_identifier
@@ -87,25 +361,31 @@ pub async fn get_recent_entries_for_user(
     )
     .fetch_all(db)
     .await
-    .unwrap()
-    .into_iter()
-    .map(|row| DataEntry {
-        id: row.id,
-        unique_identifier: row.unique_identifier,
-        value: row.value.to_2_decimal(),
-        created_at: row.created_at,
-        label: row.label,
-    })
-    .collect()
+    .unwrap();
+
+    let entry_ids: Vec<i32> = rows.iter().map(|row| row.id).collect();
+    let mut metrics_map = get_metrics_for_entries(db, &entry_ids).await;
+
+    rows.into_iter()
+        .map(|row| DataEntry {
+            id: row.id,
+            unique_identifier: row.unique_identifier,
+            created_at: row.created_at,
+            label: row.label,
+            topic: row.topic,
+            metrics: metrics_map.remove(&row.id).unwrap_or_default(),
+        })
+        .collect()
 }
 
-// Get daily averages for a user's identifiers
+// Get daily averages for a user's identifiers, with days cut at midnight in the given timezone
 pub async fn get_daily_averages_for_user(
     db: &Pool<Postgres>,
     user_id: i32,
     unique_identifiers: &str,
     days_back: i32,
-) -> AverageResponse {
+    timezone: &str,
+) -> Result<AverageResponse, sqlx::Error> {
     let identifiers: Vec<String> = unique_identifiers
         .split(',')
         .map(|s| s.trim().to_string())
@@ -126,8 +406,7 @@ pub async fn get_daily_averages_for_user(
             user_id
         )
         .fetch_all(db)
-        .await
-        .unwrap();
+        .await?;
 
         for row in labels {
             labels_map.insert(row.unique_identifier, row.label);
@@ -137,42 +416,349 @@ pub async fn get_daily_averages_for_user(
     // Only use identifiers that belong to the user
     let user_identifiers: Vec<String> = labels_map.keys().cloned().collect();
 
+    let tiers = get_tiers(db, 24 * 60 * 60).await?;
+
     for identifier in user_identifiers {
-        let averages = sqlx::query!(
+        let rows = sqlx::query!(
             r#"
-                SELECT 
-                    DATE(created_at) as date,
-                    AVG(value) as average_value,
-                    COUNT(*) as entry_count
-                FROM data_entry
-                WHERE 
-                    unique_identifier = $1
-                    AND created_at >= CURRENT_DATE - INTERVAL '1 day' * $2
-                    AND CURRENT_DATE  >= created_at - INTERVAL '1 day'
-                GROUP BY DATE(created_at)
-                ORDER BY date DESC
+                WITH range AS (
+                    SELECT
+                        (date_trunc('day', now() AT TIME ZONE $3) - INTERVAL '1 day' * $2) AT TIME ZONE $3 as start,
+                        (date_trunc('day', now() AT TIME ZONE $3) + INTERVAL '1 day') AT TIME ZONE $3 as stop
+                ),
+                source AS (
+                    SELECT de.created_at as time, dem.name, dem.unit, dem.value as sum, 1::bigint as count
+                    FROM data_entry de
+                    JOIN data_entry_metric dem ON dem.data_entry_id = de.id, range
+                    WHERE de.unique_identifier = $1
+                    AND de.created_at >= range.start AND de.created_at < range.stop
+                    AND (de.created_at >= COALESCE($4::timestamptz, '-infinity') OR de.id > (SELECT last_entry_id FROM rollup_state))
+                    UNION ALL
+                    SELECT bucket, name, unit, sum, count
+                    FROM reading_rollup_hourly, range
+                    WHERE unique_identifier = $1
+                    AND bucket >= GREATEST(range.start, COALESCE($5::timestamptz, '-infinity'))
+                    AND bucket < LEAST(range.stop, COALESCE($4::timestamptz, '-infinity'))
+                    UNION ALL
+                    -- Placed at midday, so a UTC day lands on the local day it mostly covers
+                    SELECT bucket + INTERVAL '12 hours', name, unit, sum, count
+                    FROM reading_rollup_daily, range
+                    WHERE unique_identifier = $1
+                    AND bucket + INTERVAL '12 hours' >= range.start
+                    AND bucket < LEAST(range.stop, COALESCE($5::timestamptz, '-infinity'), COALESCE($4::timestamptz, '-infinity'))
+                )
+                SELECT
+                    DATE(time AT TIME ZONE $3) as "date!",
+                    name as "name!",
+                    MAX(unit) as unit,
+                    SUM(sum) / SUM(count)::float8 as "average_value!",
+                    SUM(count)::bigint as "entry_count!"
+                FROM source
+                GROUP BY 1, 2
+                ORDER BY 1 DESC, 2
             "#,
             identifier,
-            days_back as f64
+            days_back as f64,
+            timezone,
+            tiers.raw_from,
+            tiers.hourly_from
         )
         .fetch_all(db)
-        .await
-        .unwrap()
-        .into_iter()
-        .map(|row| DailyAverage {
-            date: row.date.unwrap(),
-            average_value: row.average_value.unwrap_or(0.0).to_2_decimal(),
-            entry_count: row.entry_count.unwrap_or(0),
-        })
-        .collect();
+        .await?;
+
+        let mut averages: Vec<DailyAverage> = Vec::new();
+
+        for row in rows {
+            let average_value = row.average_value.to_2_decimal();
+
+            if averages.last().is_none_or(|day| day.date != row.date) {
+                averages.push(DailyAverage {
+                    date: row.date,
+                    average_value: None,
+                    entry_count: 0,
+                    metrics: Vec::new(),
+                    mold_index: None,
+                });
+            }
+            let Some(day) = averages.last_mut() else {
+                continue;
+            };
+            // Rollups only count values per metric, so a day counts as many
+            // readings as its most frequent metric has values
+            day.entry_count = day.entry_count.max(row.entry_count);
+            if row.name == HUMIDITY {
+                day.average_value = Some(average_value);
+            }
+            day.metrics.push(MetricAverage {
+                name: row.name,
+                unit: row.unit,
+                average_value,
+                entry_count: row.entry_count,
+            });
+        }
+
+        let mold_from = chrono::Utc::now() - chrono::Duration::days(i64::from(days_back) + 2);
+        let mold_days = get_daily_max(db, &identifier, mold_from, timezone).await?;
+        for day in averages.iter_mut() {
+            day.mold_index = mold_days.get(&day.date).map(|index| index.to_2_decimal());
+        }
 
         response_map.insert(identifier, averages);
     }
 
-    AverageResponse {
+    Ok(AverageResponse {
         identifiers: response_map,
         labels: labels_map,
+    })
+}
+
+// One metric of one bucket, as returned by the readings queries
+struct BucketRow {
+    unique_identifier: String,
+    time: chrono::DateTime<chrono::Utc>,
+    name: String,
+    unit: Option<String>,
+    min: f64,
+    max: f64,
+    avg: f64,
+    count: i64,
+}
+
+// Get readings of a user's identifiers between `from` and `to`, either raw or
+// aggregated into buckets. Day and week buckets start at local midnight in the
+// given timezone, weeks on Monday. Shorter buckets stay aligned to UTC so they
+// do not merge or split around DST changes.
+pub async fn get_readings_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    query: &ReadingsQuery,
+    to: chrono::DateTime<chrono::Utc>,
+    limit: i64,
+    timezone: &str,
+) -> Result<ReadingsResponse, sqlx::Error> {
+    let identifiers: Vec<String> = query
+        .unique_identifiers
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .collect();
+    let metric_names: Option<Vec<String>> = query.metrics.as_ref().map(|metrics| {
+        metrics
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .collect()
+    });
+
+    // Get labels and surface temperatures for the identifiers, filtering by user_id
+    let mappings = sqlx::query!(
+        r#"
+            SELECT unique_identifier, label, surface_temperature
+            FROM data_entry_mapping
+            WHERE unique_identifier = ANY($1) AND user_id = $2
+        "#,
+        &identifiers,
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+    let surface_temperatures: std::collections::HashMap<String, f64> = mappings
+        .iter()
+        .filter_map(|row| Some((row.unique_identifier.clone(), row.surface_temperature?)))
+        .collect();
+    let labels_map: std::collections::HashMap<String, String> = mappings
+        .into_iter()
+        .map(|row| (row.unique_identifier, row.label))
+        .collect();
+
+    // Only use identifiers that belong to the user
+    let user_identifiers: Vec<String> = labels_map.keys().cloned().collect();
+
+    // Both queries return one row per identifier, bucket and metric
+    let rows: Vec<BucketRow> =
+        match query.bucket.unwrap_or(Bucket::Raw).seconds() {
+            None => sqlx::query!(
+                r#"
+                    SELECT de.unique_identifier, de.created_at, dem.name, dem.unit, dem.value
+                    FROM data_entry de
+                    JOIN data_entry_metric dem ON dem.data_entry_id = de.id
+                    WHERE de.unique_identifier = ANY($1)
+                    AND de.created_at >= $2 AND de.created_at < $3
+                    AND ($4::varchar[] IS NULL OR dem.name = ANY($4))
+                    ORDER BY de.unique_identifier, de.created_at, dem.name
+                    LIMIT $5
+                "#,
+                &user_identifiers,
+                query.from,
+                to,
+                metric_names.as_deref(),
+                limit
+            )
+            .fetch_all(db)
+            .await?
+            .into_iter()
+            .map(|row| BucketRow {
+                unique_identifier: row.unique_identifier,
+                time: row.created_at,
+                name: row.name,
+                unit: row.unit,
+                min: row.value,
+                max: row.value,
+                avg: row.value,
+                count: 1,
+            })
+            .collect(),
+            Some(seconds) => {
+                let bucket_timezone = if seconds >= 24 * 60 * 60 {
+                    timezone
+                } else {
+                    "UTC"
+                };
+                let tiers = get_tiers(db, seconds).await?;
+                sqlx::query!(
+                    r#"
+                    WITH source AS (
+                        SELECT de.unique_identifier, de.created_at as time, dem.name, dem.unit,
+                            dem.value as min, dem.value as max, dem.value as sum, 1::bigint as count
+                        FROM data_entry de
+                        JOIN data_entry_metric dem ON dem.data_entry_id = de.id
+                        WHERE de.unique_identifier = ANY($1)
+                        AND de.created_at >= $2 AND de.created_at < $3
+                        AND (de.created_at >= COALESCE($8::timestamptz, '-infinity') OR de.id > (SELECT last_entry_id FROM rollup_state))
+                        UNION ALL
+                        SELECT unique_identifier, bucket, name, unit, min, max, sum, count
+                        FROM reading_rollup_hourly
+                        WHERE unique_identifier = ANY($1)
+                        AND bucket >= GREATEST($2, COALESCE($9::timestamptz, '-infinity'))
+                        AND bucket < LEAST($3, COALESCE($8::timestamptz, '-infinity'))
+                        UNION ALL
+                        -- Placed at midday, so a UTC day lands on the local day it mostly covers
+                        SELECT unique_identifier, bucket + INTERVAL '12 hours', name, unit, min, max, sum, count
+                        FROM reading_rollup_daily
+                        WHERE unique_identifier = ANY($1)
+                        AND bucket + INTERVAL '12 hours' >= $2
+                        AND bucket < LEAST($3, COALESCE($9::timestamptz, '-infinity'), COALESCE($8::timestamptz, '-infinity'))
+                    )
+                    SELECT
+                        unique_identifier as "unique_identifier!",
+                        date_bin(make_interval(secs => $5), time AT TIME ZONE $7, TIMESTAMP '2000-01-03 00:00:00') AT TIME ZONE $7 as "time!",
+                        name as "name!",
+                        MAX(unit) as unit,
+                        MIN(min) as "min!",
+                        MAX(max) as "max!",
+                        SUM(sum) / SUM(count)::float8 as "avg!",
+                        SUM(count)::bigint as "count!"
+                    FROM source
+                    WHERE ($4::varchar[] IS NULL OR name = ANY($4))
+                    GROUP BY 1, 2, 3
+                    ORDER BY 1, 2, 3
+                    LIMIT $6
+                "#,
+                    &user_identifiers,
+                    query.from,
+                    to,
+                    metric_names.as_deref(),
+                    seconds as f64,
+                    limit,
+                    bucket_timezone,
+                    tiers.raw_from,
+                    tiers.hourly_from
+                )
+                .fetch_all(db)
+                .await?
+                .into_iter()
+                .map(|row| BucketRow {
+                    unique_identifier: row.unique_identifier,
+                    time: row.time,
+                    name: row.name,
+                    unit: row.unit,
+                    min: row.min,
+                    max: row.max,
+                    avg: row.avg,
+                    count: row.count,
+                })
+                .collect()
+            }
+        };
+
+    let mut response_map: std::collections::HashMap<String, Vec<ReadingBucket>> =
+        user_identifiers
+            .into_iter()
+            .map(|identifier| (identifier, Vec::new()))
+            .collect();
+
+    for row in rows {
+        let Some(buckets) = response_map.get_mut(&row.unique_identifier) else {
+            continue;
+        };
+        if buckets.last().is_none_or(|bucket| bucket.time != row.time) {
+            buckets.push(ReadingBucket {
+                time: row.time,
+                metrics: Vec::new(),
+                condensation_risk: None,
+            });
+        }
+        if let Some(bucket) = buckets.last_mut() {
+            if row.name == DEW_POINT {
+                bucket.condensation_risk = condensation_risk(
+                    Some(row.max),
+                    surface_temperatures.get(&row.unique_identifier).copied(),
+                );
+            }
+            bucket.metrics.push(MetricStats {
+                name: row.name,
+                unit: row.unit,
+                min: row.min.to_2_decimal(),
+                max: row.max.to_2_decimal(),
+                avg: row.avg.to_2_decimal(),
+                count: row.count,
+            });
+        }
     }
+
+    Ok(ReadingsResponse {
+        from: query.from,
+        to,
+        timezone: timezone.to_string(),
+        identifiers: response_map,
+        labels: labels_map,
+    })
+}
+
+// Stream the readings of a user's mapped identifiers between `from` and `to`, one
+// row per metric, without loading them all into memory
+pub fn stream_export_rows(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    identifiers: Option<Vec<String>>,
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+    metric_names: Option<Vec<String>>,
+) -> BoxStream<'_, Result<ExportRow, sqlx::Error>> {
+    sqlx::query_as!(
+        ExportRow,
+        r#"
+            SELECT
+                de.created_at as time,
+                de.unique_identifier,
+                map.label,
+                dem.name as metric,
+                dem.value,
+                dem.unit
+            FROM data_entry_mapping map
+            JOIN data_entry de ON de.unique_identifier = map.unique_identifier
+            JOIN data_entry_metric dem ON dem.data_entry_id = de.id
+            WHERE map.user_id = $1
+            AND ($2::varchar[] IS NULL OR map.unique_identifier = ANY($2))
+            AND de.created_at >= $3 AND de.created_at < $4
+            AND ($5::varchar[] IS NULL OR dem.name = ANY($5))
+            ORDER BY de.unique_identifier, de.created_at, dem.name
+        "#,
+        user_id,
+        identifiers.as_deref(),
+        from,
+        to,
+        metric_names.as_deref()
+    )
+    .fetch(db)
 }
 
 // Get total count and counts by identifier without requiring authentication
@@ -214,3 +800,63 @@ pub async fn get_public_count_data(db: &Pool<Postgres>) -> CountResponse {
         entries_by_identifier,
     }
 }
+
+// Get the time of the newest reading of each of the given sensors. Sensors
+// without readings are left out.
+pub async fn get_last_seen(
+    db: &Pool<Postgres>,
+    unique_identifiers: &[String],
+) -> Result<std::collections::HashMap<String, chrono::DateTime<chrono::Utc>>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+            SELECT de.unique_identifier, MAX(de.created_at) as "latest_entry!"
+            FROM data_entry de
+            WHERE de.unique_identifier = ANY($1)
+            GROUP BY de.unique_identifier
+        "#,
+        unique_identifiers
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.unique_identifier, row.latest_entry))
+        .collect())
+}
+
+// One value of a metric, as read back for evaluating alert rules
+pub struct MetricValue {
+    pub unique_identifier: String,
+    pub name: String,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub value: f64,
+}
+
+// Get the values of the given metrics of the given sensors in a time range, oldest first
+pub async fn get_metric_values(
+    db: &Pool<Postgres>,
+    unique_identifiers: &[String],
+    metric_names: &[String],
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<MetricValue>, sqlx::Error> {
+    sqlx::query_as!(
+        MetricValue,
+        r#"
+            SELECT de.unique_identifier, dem.name, de.created_at as time, dem.value
+            FROM data_entry de
+            JOIN data_entry_metric dem ON dem.data_entry_id = de.id
+            WHERE de.unique_identifier = ANY($1)
+            AND dem.name = ANY($2)
+            AND de.created_at >= $3 AND de.created_at <= $4
+            ORDER BY de.created_at
+        "#,
+        unique_identifiers,
+        metric_names,
+        from,
+        to
+    )
+    .fetch_all(db)
+    .await
+}
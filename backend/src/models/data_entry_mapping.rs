@@ -1,7 +1,9 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::{Pool, Postgres};
 use chrono::{DateTime, Utc};
 
+use crate::models::alert;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DataEntryMapping {
     #[serde(skip_deserializing)]
@@ -12,18 +14,33 @@ pub struct DataEntryMapping {
     pub user_id: i32,
     #[serde(skip_deserializing)]
     pub created_at: Option<DateTime<Utc>>,
+    // Temperature of the coldest surface in the room, like a window or an outer wall
+    pub surface_temperature: Option<f64>,
 }
 
 #[derive(Deserialize)]
 pub struct CreateDataEntryMapping {
     pub unique_identifier: String,
     pub label: String,
+    pub surface_temperature: Option<f64>,
 }
 
 #[derive(Deserialize)]
 pub struct UpdateDataEntryMapping {
     pub unique_identifier: Option<String>,
     pub label: Option<String>,
+    // Null clears the surface temperature, leaving it out keeps it
+    #[serde(default, deserialize_with = "nullable")]
+    pub surface_temperature: Option<Option<f64>>,
+}
+
+// Tell a field set to null apart from one that is left out
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
 }
 
 // Create a new data entry mapping
@@ -35,13 +52,14 @@ pub async fn create(
     let result = sqlx::query_as!(
         DataEntryMapping,
         r#"
-        INSERT INTO data_entry_mapping (unique_identifier, label, user_id)
-        VALUES ($1, $2, $3)
-        RETURNING id, unique_identifier, label, user_id, created_at
+        INSERT INTO data_entry_mapping (unique_identifier, label, user_id, surface_temperature)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, unique_identifier, label, user_id, created_at, surface_temperature
         "#,
         mapping.unique_identifier,
         mapping.label,
-        user_id
+        user_id,
+        mapping.surface_temperature
     )
     .fetch_one(db)
     .await?;
@@ -54,7 +72,7 @@ pub async fn get_all_for_user(db: &Pool<Postgres>, user_id: i32) -> Result<Vec<D
     let mappings = sqlx::query_as!(
         DataEntryMapping,
         r#"
-        SELECT id, unique_identifier, label, user_id, created_at
+        SELECT id, unique_identifier, label, user_id, created_at, surface_temperature
         FROM data_entry_mapping
         WHERE user_id = $1
         ORDER BY id
@@ -72,7 +90,7 @@ pub async fn get_by_id_for_user(db: &Pool<Postgres>, id: i32, user_id: i32) -> R
     let mapping = sqlx::query_as!(
         DataEntryMapping,
         r#"
-        SELECT id, unique_identifier, label, user_id, created_at
+        SELECT id, unique_identifier, label, user_id, created_at, surface_temperature
         FROM data_entry_mapping
         WHERE id = $1 AND user_id = $2
         "#,
@@ -96,9 +114,15 @@ pub async fn update(
     let existing = get_by_id_for_user(db, id, user_id).await?;
     
     if let Some(existing) = existing {
+        let mut tx = db.begin().await?;
+
         // Update the fields that are provided
-        let unique_identifier = mapping.unique_identifier.unwrap_or(existing.unique_identifier);
+        let previous = existing.unique_identifier;
+        let unique_identifier = mapping.unique_identifier.unwrap_or(previous.clone());
         let label = mapping.label.unwrap_or(existing.label);
+        let surface_temperature = mapping
+            .surface_temperature
+            .unwrap_or(existing.surface_temperature);
 
         let updated = sqlx::query_as!(
             DataEntryMapping,
@@ -106,17 +130,25 @@ pub async fn update(
             UPDATE data_entry_mapping
             SET 
                 unique_identifier = $1,
-                label = $2
+                label = $2,
+                surface_temperature = $5
             WHERE id = $3 AND user_id = $4
-            RETURNING id, unique_identifier, label, user_id, created_at
+            RETURNING id, unique_identifier, label, user_id, created_at, surface_temperature
             "#,
             unique_identifier,
             label,
             id,
-            user_id
+            user_id,
+            surface_temperature
         )
-        .fetch_one(db)
+        .fetch_one(&mut *tx)
         .await?;
+        // Rules on the sensor the mapping pointed to before go with it
+        if previous != updated.unique_identifier {
+            alert::delete_unmapped(&mut tx, &previous).await?;
+        }
+
+        tx.commit().await?;
 
         Ok(Some(updated))
     } else {
@@ -124,15 +156,44 @@ pub async fn update(
     }
 }
 
-// Delete a data entry mapping
+// Delete a data entry mapping along with the user's alert rules on the sensor
 pub async fn delete(db: &Pool<Postgres>, id: i32, user_id: i32) -> Result<bool, sqlx::Error> {
-    let result = sqlx::query!(
-        "DELETE FROM data_entry_mapping WHERE id = $1 AND user_id = $2", 
+    let mut tx = db.begin().await?;
+
+    let deleted = sqlx::query_scalar!(
+        "DELETE FROM data_entry_mapping WHERE id = $1 AND user_id = $2 RETURNING unique_identifier",
         id,
         user_id
     )
-    .execute(db)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(unique_identifier) = deleted else {
+        return Ok(false);
+    };
+    alert::delete_unmapped(&mut tx, &unique_identifier).await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+// Whether the user has mapped the identifier
+pub async fn is_mapped(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    user_id: i32,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM data_entry_mapping WHERE unique_identifier = $1 AND user_id = $2
+        ) as "mapped!"
+        "#,
+        unique_identifier,
+        user_id
+    )
+    .fetch_one(db)
     .await?;
 
-    Ok(result.rows_affected() > 0)
-} 
+    Ok(result.mapped)
+}
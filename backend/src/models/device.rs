@@ -0,0 +1,419 @@
+use bcrypt::{DEFAULT_COST, hash, verify};
+use chrono::{DateTime, Utc};
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+
+use crate::models::alert;
+
+#[derive(Serialize, Debug)]
+pub struct Device {
+    pub id: i32,
+    pub unique_identifier: String,
+    pub owner_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    // Readings rejected because they carried a missing or wrong token
+    pub rejected_count: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ClaimDevice {
+    pub unique_identifier: String,
+    pub claim_code: String,
+    // Label for the mapping created for the new owner
+    pub label: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TransferDevice {
+    pub username: String,
+}
+
+// Outcome of trying to claim a device
+pub enum ClaimResult {
+    Claimed(Device),
+    NotFound,
+    InvalidCode,
+    // Owned by another account, which has to transfer it
+    AlreadyOwned,
+}
+
+// Characters that are easy to read off a display or label
+const CLAIM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+// Generate a claim code like "K7QM-2XPD"
+pub fn generate_claim_code() -> String {
+    let mut rng = rand::thread_rng();
+    let code: String = (0..8)
+        .map(|_| CLAIM_CODE_ALPHABET[rng.gen_range(0..CLAIM_CODE_ALPHABET.len())] as char)
+        .collect();
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+// Claim codes are typed in by hand, so ignore case, dashes and spaces
+fn normalize_claim_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+// Claim codes are short, so they get a slow hash like passwords do
+pub fn hash_claim_code(code: &str) -> String {
+    hash(normalize_claim_code(code), DEFAULT_COST).unwrap()
+}
+
+// Generate a new random device token
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// Tokens are long random strings, so a fast hash is enough and keeps per-message checks cheap
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Register a new device
+pub async fn create(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    token_hash: &str,
+    claim_code_hash: &str,
+) -> Result<Device, sqlx::Error> {
+    let device = sqlx::query_as!(
+        Device,
+        r#"
+        INSERT INTO device (unique_identifier, token_hash, claim_code_hash)
+        VALUES ($1, $2, $3)
+        RETURNING id, unique_identifier, owner_id, created_at, last_seen_at, rejected_count
+        "#,
+        unique_identifier,
+        token_hash,
+        claim_code_hash
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(device)
+}
+
+// Replace the token of a device
+pub async fn update_token(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    token_hash: &str,
+) -> Result<Option<Device>, sqlx::Error> {
+    let device = sqlx::query_as!(
+        Device,
+        r#"
+        UPDATE device
+        SET token_hash = $2
+        WHERE unique_identifier = $1
+        RETURNING id, unique_identifier, owner_id, created_at, last_seen_at, rejected_count
+        "#,
+        unique_identifier,
+        token_hash
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(device)
+}
+
+// Replace the claim code of a device
+pub async fn update_claim_code(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    claim_code_hash: &str,
+) -> Result<Option<Device>, sqlx::Error> {
+    let device = sqlx::query_as!(
+        Device,
+        r#"
+        UPDATE device
+        SET claim_code_hash = $2
+        WHERE unique_identifier = $1
+        RETURNING id, unique_identifier, owner_id, created_at, last_seen_at, rejected_count
+        "#,
+        unique_identifier,
+        claim_code_hash
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(device)
+}
+
+// Remove the owner of a device so it can be claimed again
+pub async fn release(db: &Pool<Postgres>, unique_identifier: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE device SET owner_id = NULL WHERE unique_identifier = $1",
+        unique_identifier
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Get all devices owned by a user
+pub async fn get_all_for_owner(db: &Pool<Postgres>, user_id: i32) -> Result<Vec<Device>, sqlx::Error> {
+    let devices = sqlx::query_as!(
+        Device,
+        r#"
+        SELECT id, unique_identifier, owner_id, created_at, last_seen_at, rejected_count
+        FROM device
+        WHERE owner_id = $1
+        ORDER BY unique_identifier
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(devices)
+}
+
+// Whether the user owns the device
+pub async fn is_owner(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    user_id: i32,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM device WHERE unique_identifier = $1 AND owner_id = $2
+        ) as "owned!"
+        "#,
+        unique_identifier,
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(result.owned)
+}
+
+// Claim an unowned device with its claim code. Mappings other accounts made for
+// the device are removed, and the new owner gets one with the given label.
+pub async fn claim(
+    db: &Pool<Postgres>,
+    claim: ClaimDevice,
+    user_id: i32,
+) -> Result<ClaimResult, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let existing = sqlx::query!(
+        r#"
+        SELECT owner_id, claim_code_hash
+        FROM device
+        WHERE unique_identifier = $1
+        FOR UPDATE
+        "#,
+        claim.unique_identifier
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(existing) = existing else {
+        return Ok(ClaimResult::NotFound);
+    };
+
+    let code_valid = existing
+        .claim_code_hash
+        .is_some_and(|code_hash| {
+            verify(normalize_claim_code(&claim.claim_code), &code_hash).unwrap_or(false)
+        });
+    if !code_valid {
+        return Ok(ClaimResult::InvalidCode);
+    }
+    if existing.owner_id.is_some_and(|owner_id| owner_id != user_id) {
+        return Ok(ClaimResult::AlreadyOwned);
+    }
+
+    let device = sqlx::query_as!(
+        Device,
+        r#"
+        UPDATE device
+        SET owner_id = $2
+        WHERE unique_identifier = $1
+        RETURNING id, unique_identifier, owner_id, created_at, last_seen_at, rejected_count
+        "#,
+        claim.unique_identifier,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM data_entry_mapping WHERE unique_identifier = $1 AND user_id <> $2",
+        claim.unique_identifier,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    alert::delete_unmapped(&mut tx, &claim.unique_identifier).await?;
+
+    if let Some(label) = claim.label {
+        sqlx::query!(
+            r#"
+            INSERT INTO data_entry_mapping (unique_identifier, label, user_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, unique_identifier) DO UPDATE SET label = EXCLUDED.label
+            "#,
+            claim.unique_identifier,
+            label,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(ClaimResult::Claimed(device))
+}
+
+// Hand a device over to another account. The current owner's mapping moves
+// along with it, so the new owner keeps the label and history.
+pub async fn transfer(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    owner_id: i32,
+    new_owner_id: i32,
+) -> Result<Option<Device>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let device = sqlx::query_as!(
+        Device,
+        r#"
+        UPDATE device
+        SET owner_id = $3
+        WHERE unique_identifier = $1 AND owner_id = $2
+        RETURNING id, unique_identifier, owner_id, created_at, last_seen_at, rejected_count
+        "#,
+        unique_identifier,
+        owner_id,
+        new_owner_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if device.is_none() {
+        return Ok(None);
+    }
+
+    sqlx::query!(
+        "DELETE FROM data_entry_mapping WHERE unique_identifier = $1 AND user_id <> $2",
+        unique_identifier,
+        owner_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE data_entry_mapping SET user_id = $3 WHERE unique_identifier = $1 AND user_id = $2",
+        unique_identifier,
+        owner_id,
+        new_owner_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    // The previous owner's rules go, as their channels are of no use to the new owner
+    alert::delete_unmapped(&mut tx, unique_identifier).await?;
+
+    tx.commit().await?;
+
+    Ok(device)
+}
+
+// Get all registered devices
+pub async fn get_all(db: &Pool<Postgres>) -> Result<Vec<Device>, sqlx::Error> {
+    let devices = sqlx::query_as!(
+        Device,
+        r#"
+        SELECT id, unique_identifier, owner_id, created_at, last_seen_at, rejected_count
+        FROM device
+        ORDER BY unique_identifier
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(devices)
+}
+
+// Remove a device from the registry
+pub async fn delete(db: &Pool<Postgres>, unique_identifier: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM device WHERE unique_identifier = $1",
+        unique_identifier
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Get the token hashes of the registered devices among the given identifiers
+pub async fn get_token_hashes(
+    db: &Pool<Postgres>,
+    unique_identifiers: &[String],
+) -> Result<HashMap<String, String>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT unique_identifier, token_hash
+        FROM device
+        WHERE unique_identifier = ANY($1)
+        "#,
+        unique_identifiers
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.unique_identifier, row.token_hash))
+        .collect())
+}
+
+// Count rejected readings, once per occurrence of an identifier
+pub async fn record_rejections(
+    db: &Pool<Postgres>,
+    unique_identifiers: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE device
+        SET rejected_count = device.rejected_count + rejected.count
+        FROM (
+            SELECT unique_identifier, COUNT(*)::int AS count
+            FROM UNNEST($1::varchar[]) AS unique_identifier
+            GROUP BY unique_identifier
+        ) rejected
+        WHERE device.unique_identifier = rejected.unique_identifier
+        "#,
+        unique_identifiers
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+// Record that the devices have sent authenticated readings
+pub async fn touch(db: &Pool<Postgres>, unique_identifiers: &[String]) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE device SET last_seen_at = CURRENT_TIMESTAMP WHERE unique_identifier = ANY($1)",
+        unique_identifiers
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
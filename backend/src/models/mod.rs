@@ -1,3 +1,12 @@
 pub mod data_entry;
 pub mod data_entry_mapping;
-pub mod app_user; 
\ No newline at end of file
+pub mod app_user;
+pub mod rejected_message;
+pub mod device;
+pub mod sensor;
+pub mod rollup;
+pub mod alert;
+pub mod notification;
+pub mod mold;
+pub mod weather;
+pub mod ventilation;
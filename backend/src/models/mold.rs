@@ -0,0 +1,335 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+
+use crate::models::data_entry::{Bucket, HUMIDITY, Round};
+use crate::models::rollup::get_tiers;
+use crate::models::weather::WEATHER_PREFIX;
+
+// Metric name alert rules use to watch the mold index
+pub const MOLD_INDEX: &str = "mold_index";
+
+// Where the model of a sensor stopped
+#[derive(Clone, Debug)]
+pub struct MoldState {
+    pub unique_identifier: String,
+    // Mold index on the VTT scale, from 0 (no growth) to 6 (fully covered)
+    pub mold_index: f64,
+    // Hours since conditions last favoured growth
+    pub dry_hours: i32,
+    pub computed_until: DateTime<Utc>,
+    pub last_entry_id: i32,
+}
+
+// Average humidity and temperature of an hour
+pub struct HourlyClimate {
+    pub hour: DateTime<Utc>,
+    pub humidity: f64,
+    pub temperature: Option<f64>,
+}
+
+// Mold index at the end of an hour
+pub struct MoldPoint {
+    pub hour: DateTime<Utc>,
+    pub value: f64,
+    pub humidity: f64,
+    pub temperature: f64,
+    pub favourable: bool,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MoldRiskLevel {
+    // Below 1, no growth
+    None,
+    // Below 2, some growth under a microscope
+    Low,
+    // Below 3, moderate growth under a microscope
+    Moderate,
+    // 3 and up, growth can be seen
+    High,
+}
+
+impl MoldRiskLevel {
+    pub fn from_index(index: f64) -> Self {
+        match index {
+            index if index < 1.0 => MoldRiskLevel::None,
+            index if index < 2.0 => MoldRiskLevel::Low,
+            index if index < 3.0 => MoldRiskLevel::Moderate,
+            _ => MoldRiskLevel::High,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MoldRiskQuery {
+    pub from: DateTime<Utc>,
+    // Defaults to now
+    pub to: Option<DateTime<Utc>>,
+    // Defaults to days
+    pub bucket: Option<Bucket>,
+    // IANA timezone overriding the user's own
+    pub tz: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct MoldRiskPoint {
+    pub time: DateTime<Utc>,
+    // Highest index within the bucket
+    pub index: f64,
+    pub level: MoldRiskLevel,
+    // Hours within the bucket that were humid and warm enough for growth
+    pub favourable_hours: i64,
+}
+
+#[derive(Serialize)]
+pub struct MoldRiskResponse {
+    pub unique_identifier: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub timezone: String,
+    // Latest index, None before the model has run for the sensor
+    pub index: Option<f64>,
+    pub level: Option<MoldRiskLevel>,
+    pub points: Vec<MoldRiskPoint>,
+}
+
+// Get the sensors to model, those indoors that are mapped by someone
+pub async fn get_identifiers(db: &Pool<Postgres>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT unique_identifier FROM data_entry_mapping
+        WHERE NOT starts_with(unique_identifier, $1)
+        ORDER BY unique_identifier
+        "#,
+        WEATHER_PREFIX
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn get_state(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+) -> Result<Option<MoldState>, sqlx::Error> {
+    sqlx::query_as!(
+        MoldState,
+        r#"
+        SELECT unique_identifier, mold_index, dry_hours, computed_until, last_entry_id
+        FROM mold_state
+        WHERE unique_identifier = $1
+        "#,
+        unique_identifier
+    )
+    .fetch_optional(db)
+    .await
+}
+
+// Whether readings for hours before `before` were stored after reading `after_id`,
+// e.g. because they were imported
+pub async fn has_late_readings(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    after_id: i32,
+    before: DateTime<Utc>,
+) -> Result<bool, sqlx::Error> {
+    let exists = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM data_entry
+            WHERE unique_identifier = $1 AND id > $2 AND created_at < $3
+        ) as "exists!"
+        "#,
+        unique_identifier,
+        after_id,
+        before
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(exists)
+}
+
+// Get the hourly humidity and temperature of a sensor in a range of whole
+// hours. Hours that are rolled up are read from the rollups, so the history
+// is there even after raw readings expired.
+pub async fn get_hourly_climate(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<HourlyClimate>, sqlx::Error> {
+    let tiers = get_tiers(db, 60 * 60).await?;
+
+    let rows = sqlx::query!(
+        r#"
+            WITH source AS (
+                SELECT date_trunc('hour', de.created_at) as hour, dem.name, dem.value as sum, 1::bigint as count
+                FROM data_entry de
+                JOIN data_entry_metric dem ON dem.data_entry_id = de.id
+                WHERE de.unique_identifier = $1
+                AND de.created_at >= GREATEST($2, COALESCE($4::timestamptz, '-infinity')) AND de.created_at < $3
+                AND dem.name IN ('humidity', 'temperature')
+                UNION ALL
+                SELECT bucket, name, sum, count
+                FROM reading_rollup_hourly
+                WHERE unique_identifier = $1
+                AND bucket >= $2
+                AND bucket < LEAST($3, COALESCE($4::timestamptz, '-infinity'))
+                AND name IN ('humidity', 'temperature')
+            )
+            SELECT hour as "hour!", name as "name!", SUM(sum) / SUM(count)::float8 as "average!"
+            FROM source
+            GROUP BY 1, 2
+            ORDER BY 1, 2
+        "#,
+        unique_identifier,
+        from,
+        to,
+        tiers.raw_from
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut hours: Vec<HourlyClimate> = Vec::new();
+    let mut temperatures = HashMap::new();
+    for row in rows {
+        if row.name == HUMIDITY {
+            hours.push(HourlyClimate {
+                hour: row.hour,
+                humidity: row.average,
+                temperature: None,
+            });
+        } else {
+            temperatures.insert(row.hour, row.average);
+        }
+    }
+    for hour in hours.iter_mut() {
+        hour.temperature = temperatures.get(&hour.hour).copied();
+    }
+
+    Ok(hours)
+}
+
+// Store the index of the hours just computed and where the model stopped
+pub async fn save(
+    db: &Pool<Postgres>,
+    state: &MoldState,
+    points: &[MoldPoint],
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let hours: Vec<DateTime<Utc>> = points.iter().map(|point| point.hour).collect();
+    let values: Vec<f64> = points.iter().map(|point| point.value).collect();
+    let humidities: Vec<f64> = points.iter().map(|point| point.humidity).collect();
+    let temperatures: Vec<f64> = points.iter().map(|point| point.temperature).collect();
+    let favourable: Vec<bool> = points.iter().map(|point| point.favourable).collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO mold_index (unique_identifier, hour, value, humidity, temperature, favourable)
+        SELECT $1, * FROM UNNEST($2::timestamptz[], $3::float8[], $4::float8[], $5::float8[], $6::bool[])
+        ON CONFLICT (unique_identifier, hour) DO UPDATE
+        SET value = EXCLUDED.value, humidity = EXCLUDED.humidity,
+            temperature = EXCLUDED.temperature, favourable = EXCLUDED.favourable
+        "#,
+        state.unique_identifier,
+        &hours,
+        &values,
+        &humidities,
+        &temperatures,
+        &favourable
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO mold_state (unique_identifier, mold_index, dry_hours, computed_until, last_entry_id)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (unique_identifier) DO UPDATE
+        SET mold_index = EXCLUDED.mold_index, dry_hours = EXCLUDED.dry_hours,
+            computed_until = EXCLUDED.computed_until, last_entry_id = EXCLUDED.last_entry_id
+        "#,
+        state.unique_identifier,
+        state.mold_index,
+        state.dry_hours,
+        state.computed_until,
+        state.last_entry_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
+
+// Get the mold index of a sensor in buckets. Day and week buckets follow the
+// timezone, shorter ones UTC.
+pub async fn get_series(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    bucket_seconds: i64,
+    timezone: &str,
+) -> Result<Vec<MoldRiskPoint>, sqlx::Error> {
+    let bucket_timezone = if bucket_seconds >= 24 * 60 * 60 {
+        timezone
+    } else {
+        "UTC"
+    };
+
+    let rows = sqlx::query!(
+        r#"
+            SELECT
+                date_bin(make_interval(secs => $4), hour AT TIME ZONE $5, TIMESTAMP '2000-01-03 00:00:00') AT TIME ZONE $5 as "time!",
+                MAX(value) as "index!",
+                COUNT(*) FILTER (WHERE favourable) as "favourable_hours!"
+            FROM mold_index
+            WHERE unique_identifier = $1 AND hour >= $2 AND hour < $3
+            GROUP BY 1
+            ORDER BY 1
+        "#,
+        unique_identifier,
+        from,
+        to,
+        bucket_seconds as f64,
+        bucket_timezone
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| MoldRiskPoint {
+            time: row.time,
+            index: row.index.to_2_decimal(),
+            level: MoldRiskLevel::from_index(row.index),
+            favourable_hours: row.favourable_hours,
+        })
+        .collect())
+}
+
+// Get the highest mold index of each local day since `from`
+pub async fn get_daily_max(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    from: DateTime<Utc>,
+    timezone: &str,
+) -> Result<HashMap<NaiveDate, f64>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+            SELECT DATE(hour AT TIME ZONE $3) as "date!", MAX(value) as "index!"
+            FROM mold_index
+            WHERE unique_identifier = $1 AND hour >= $2
+            GROUP BY 1
+        "#,
+        unique_identifier,
+        from,
+        timezone
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| (row.date, row.index)).collect())
+}
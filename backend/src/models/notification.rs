@@ -0,0 +1,425 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{Pool, Postgres, Transaction};
+
+// How a channel delivers notifications
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ChannelConfig {
+    // JSON POST, signed with HMAC-SHA256 when a secret is set
+    Webhook { url: String, secret: Option<String> },
+    // Sent through the server's SMTP relay
+    Email { to: String },
+    // Topic URL on an ntfy server, e.g. https://ntfy.sh/my-topic
+    Ntfy { url: String, token: Option<String> },
+    // Base URL of a Gotify server and an application token
+    Gotify { url: String, token: String },
+}
+
+#[derive(Serialize, Debug)]
+pub struct NotificationChannel {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub config: Json<ChannelConfig>,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateNotificationChannel {
+    pub name: String,
+    pub config: ChannelConfig,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateNotificationChannel {
+    pub name: Option<String>,
+    pub config: Option<ChannelConfig>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RuleChannels {
+    pub channel_ids: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationKind {
+    Fired,
+    Resolved,
+    Test,
+}
+
+// What is sent, stored with each delivery so retries send the same thing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub title: String,
+    pub message: String,
+    pub rule_id: Option<i32>,
+    pub unique_identifier: Option<String>,
+    pub value: Option<f64>,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    // Gave up after the last retry
+    Failed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NotificationDelivery {
+    pub id: i32,
+    pub channel_id: i32,
+    pub event_id: Option<i32>,
+    pub payload: Json<Notification>,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct DeliveryQuery {
+    pub channel_id: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+// A delivery picked up for sending, with the channel to send it through
+pub struct DueDelivery {
+    pub id: i32,
+    pub attempts: i32,
+    pub payload: Json<Notification>,
+    pub config: Json<ChannelConfig>,
+}
+
+// Create a new notification channel
+pub async fn create(
+    db: &Pool<Postgres>,
+    channel: CreateNotificationChannel,
+    user_id: i32,
+) -> Result<NotificationChannel, sqlx::Error> {
+    let result = sqlx::query_as!(
+        NotificationChannel,
+        r#"
+        INSERT INTO notification_channel (user_id, name, config, enabled)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, user_id, name, config as "config: Json<ChannelConfig>", enabled, created_at
+        "#,
+        user_id,
+        channel.name,
+        Json(channel.config) as _,
+        channel.enabled.unwrap_or(true)
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(result)
+}
+
+// Get all notification channels of a user
+pub async fn get_all_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+) -> Result<Vec<NotificationChannel>, sqlx::Error> {
+    let channels = sqlx::query_as!(
+        NotificationChannel,
+        r#"
+        SELECT id, user_id, name, config as "config: Json<ChannelConfig>", enabled, created_at
+        FROM notification_channel
+        WHERE user_id = $1
+        ORDER BY id
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(channels)
+}
+
+// Get a single notification channel by ID and verify user ownership
+pub async fn get_by_id_for_user(
+    db: &Pool<Postgres>,
+    id: i32,
+    user_id: i32,
+) -> Result<Option<NotificationChannel>, sqlx::Error> {
+    let channel = sqlx::query_as!(
+        NotificationChannel,
+        r#"
+        SELECT id, user_id, name, config as "config: Json<ChannelConfig>", enabled, created_at
+        FROM notification_channel
+        WHERE id = $1 AND user_id = $2
+        "#,
+        id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(channel)
+}
+
+// Update a notification channel
+pub async fn update(
+    db: &Pool<Postgres>,
+    id: i32,
+    channel: UpdateNotificationChannel,
+    user_id: i32,
+) -> Result<Option<NotificationChannel>, sqlx::Error> {
+    let Some(existing) = get_by_id_for_user(db, id, user_id).await? else {
+        return Ok(None);
+    };
+
+    let updated = sqlx::query_as!(
+        NotificationChannel,
+        r#"
+        UPDATE notification_channel
+        SET name = $1, config = $2, enabled = $3
+        WHERE id = $4 AND user_id = $5
+        RETURNING id, user_id, name, config as "config: Json<ChannelConfig>", enabled, created_at
+        "#,
+        channel.name.unwrap_or(existing.name),
+        channel.config.map(Json).unwrap_or(existing.config) as _,
+        channel.enabled.unwrap_or(existing.enabled),
+        id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(updated)
+}
+
+// Delete a notification channel and its delivery log
+pub async fn delete(db: &Pool<Postgres>, id: i32, user_id: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM notification_channel WHERE id = $1 AND user_id = $2",
+        id,
+        user_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Get the IDs of the channels an alert rule notifies
+pub async fn get_rule_channel_ids(
+    db: &Pool<Postgres>,
+    rule_id: i32,
+) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT channel_id FROM alert_rule_channel WHERE rule_id = $1 ORDER BY channel_id",
+        rule_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+// Replace the channels an alert rule notifies. Nothing changes and None is
+// returned when one of the channels is not the user's.
+pub async fn set_rule_channels(
+    db: &Pool<Postgres>,
+    rule_id: i32,
+    channel_ids: &[i32],
+    user_id: i32,
+) -> Result<Option<Vec<i32>>, sqlx::Error> {
+    let mut channel_ids = channel_ids.to_vec();
+    channel_ids.sort();
+    channel_ids.dedup();
+
+    let mut tx = db.begin().await?;
+
+    sqlx::query!("DELETE FROM alert_rule_channel WHERE rule_id = $1", rule_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let attached = sqlx::query_scalar!(
+        r#"
+        INSERT INTO alert_rule_channel (rule_id, channel_id)
+        SELECT $1, id FROM notification_channel WHERE id = ANY($2) AND user_id = $3
+        RETURNING channel_id
+        "#,
+        rule_id,
+        &channel_ids,
+        user_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if attached.len() != channel_ids.len() {
+        return Ok(None);
+    }
+    tx.commit().await?;
+
+    Ok(Some(channel_ids))
+}
+
+// Queue a notification for every enabled channel of an alert rule
+pub async fn enqueue_for_rule(
+    tx: &mut Transaction<'_, Postgres>,
+    rule_id: i32,
+    event_id: Option<i32>,
+    notification: &Notification,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO notification_delivery (channel_id, event_id, payload)
+        SELECT nc.id, $2, $3
+        FROM alert_rule_channel arc
+        JOIN notification_channel nc ON nc.id = arc.channel_id
+        WHERE arc.rule_id = $1 AND nc.enabled
+        "#,
+        rule_id,
+        event_id,
+        Json(notification) as _
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+// Record a notification that was sent right away, e.g. a test
+pub async fn create_delivery(
+    db: &Pool<Postgres>,
+    channel_id: i32,
+    notification: &Notification,
+    error: Option<&str>,
+) -> Result<NotificationDelivery, sqlx::Error> {
+    let delivery = sqlx::query_as!(
+        NotificationDelivery,
+        r#"
+        INSERT INTO notification_delivery
+            (channel_id, payload, status, attempts, last_error, delivered_at)
+        VALUES ($1, $2, CASE WHEN $3::text IS NULL THEN 'delivered' ELSE 'failed' END, 1, $3,
+            CASE WHEN $3::text IS NULL THEN CURRENT_TIMESTAMP END)
+        RETURNING id, channel_id, event_id, payload as "payload: Json<Notification>",
+            status as "status: DeliveryStatus", attempts, last_error, next_attempt_at,
+            created_at, delivered_at
+        "#,
+        channel_id,
+        Json(notification) as _,
+        error
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(delivery)
+}
+
+// Pick up deliveries that are due. They are leased for a while, so another
+// instance does not send them too if this one dies while sending.
+pub async fn claim_due(
+    db: &Pool<Postgres>,
+    limit: i64,
+    lease_seconds: f64,
+) -> Result<Vec<DueDelivery>, sqlx::Error> {
+    sqlx::query_as!(
+        DueDelivery,
+        r#"
+        WITH due AS (
+            UPDATE notification_delivery
+            SET next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM notification_delivery
+                WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, channel_id, attempts, payload
+        )
+        SELECT due.id, due.attempts, due.payload as "payload: Json<Notification>",
+            nc.config as "config: Json<ChannelConfig>"
+        FROM due
+        JOIN notification_channel nc ON nc.id = due.channel_id
+        "#,
+        limit,
+        lease_seconds
+    )
+    .fetch_all(db)
+    .await
+}
+
+// Record a successful attempt
+pub async fn mark_delivered(db: &Pool<Postgres>, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE notification_delivery
+        SET status = 'delivered', attempts = attempts + 1, last_error = NULL,
+            delivered_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        "#,
+        id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+// Record a failed attempt, to be retried at `retry_at` or given up when None
+pub async fn mark_failed(
+    db: &Pool<Postgres>,
+    id: i32,
+    error: &str,
+    retry_at: Option<DateTime<Utc>>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE notification_delivery
+        SET status = CASE WHEN $3::timestamptz IS NULL THEN 'failed' ELSE 'pending' END,
+            attempts = attempts + 1,
+            last_error = $2,
+            next_attempt_at = COALESCE($3, next_attempt_at)
+        WHERE id = $1
+        "#,
+        id,
+        error,
+        retry_at
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+// Get the newest deliveries on a user's channels
+pub async fn get_deliveries_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    query: &DeliveryQuery,
+) -> Result<Vec<NotificationDelivery>, sqlx::Error> {
+    sqlx::query_as!(
+        NotificationDelivery,
+        r#"
+        SELECT nd.id, nd.channel_id, nd.event_id, nd.payload as "payload: Json<Notification>",
+            nd.status as "status: DeliveryStatus", nd.attempts, nd.last_error, nd.next_attempt_at,
+            nd.created_at, nd.delivered_at
+        FROM notification_delivery nd
+        JOIN notification_channel nc ON nc.id = nd.channel_id
+        WHERE nc.user_id = $1
+        AND ($2::int4 IS NULL OR nd.channel_id = $2)
+        ORDER BY nd.created_at DESC, nd.id DESC
+        LIMIT $3
+        "#,
+        user_id,
+        query.channel_id,
+        query.limit.unwrap_or(100)
+    )
+    .fetch_all(db)
+    .await
+}
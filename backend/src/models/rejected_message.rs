@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use chrono::{DateTime, Utc};
+
+#[derive(Serialize, Debug)]
+pub struct RejectedMessage {
+    pub id: i32,
+    pub payload: String,
+    pub reason: String,
+    pub topic: Option<String>,
+    pub unique_identifier: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub replayed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct RejectedMessageQuery {
+    pub limit: Option<i64>,
+    pub include_replayed: Option<bool>,
+}
+
+// The device a payload claims to be from, when it is a JSON object naming one
+fn identifier_of(payload: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(payload)
+        .ok()?
+        .get("mac")?
+        .as_str()
+        .map(str::to_string)
+}
+
+// Quarantine a payload that could not be ingested
+pub async fn create(
+    db: &Pool<Postgres>,
+    payload: &str,
+    reason: &str,
+    topic: Option<&str>,
+) -> Result<RejectedMessage, sqlx::Error> {
+    let result = sqlx::query_as!(
+        RejectedMessage,
+        r#"
+        INSERT INTO rejected_message (payload, reason, topic, unique_identifier)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, payload, reason, topic, unique_identifier, created_at, replayed_at
+        "#,
+        payload,
+        reason,
+        topic,
+        identifier_of(payload)
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(result)
+}
+
+// Get the most recent rejected messages, newest first
+pub async fn get_all(
+    db: &Pool<Postgres>,
+    limit: i64,
+    include_replayed: bool,
+) -> Result<Vec<RejectedMessage>, sqlx::Error> {
+    let messages = sqlx::query_as!(
+        RejectedMessage,
+        r#"
+        SELECT id, payload, reason, topic, unique_identifier, created_at, replayed_at
+        FROM rejected_message
+        WHERE $2 OR replayed_at IS NULL
+        ORDER BY created_at DESC
+        LIMIT $1
+        "#,
+        limit,
+        include_replayed
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(messages)
+}
+
+// Get a single rejected message by ID
+pub async fn get_by_id(db: &Pool<Postgres>, id: i32) -> Result<Option<RejectedMessage>, sqlx::Error> {
+    let message = sqlx::query_as!(
+        RejectedMessage,
+        r#"
+        SELECT id, payload, reason, topic, unique_identifier, created_at, replayed_at
+        FROM rejected_message
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(message)
+}
+
+// Get the most recent rejected messages of the devices a user owns, newest first
+pub async fn get_all_for_owner(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    limit: i64,
+    include_replayed: bool,
+) -> Result<Vec<RejectedMessage>, sqlx::Error> {
+    let messages = sqlx::query_as!(
+        RejectedMessage,
+        r#"
+        SELECT rm.id, rm.payload, rm.reason, rm.topic, rm.unique_identifier, rm.created_at,
+            rm.replayed_at
+        FROM rejected_message rm
+        JOIN device d ON d.unique_identifier = rm.unique_identifier
+        WHERE d.owner_id = $1 AND ($3 OR rm.replayed_at IS NULL)
+        ORDER BY rm.created_at DESC
+        LIMIT $2
+        "#,
+        user_id,
+        limit,
+        include_replayed
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(messages)
+}
+
+// Get a single rejected message by ID and verify the user owns its device
+pub async fn get_by_id_for_owner(
+    db: &Pool<Postgres>,
+    id: i32,
+    user_id: i32,
+) -> Result<Option<RejectedMessage>, sqlx::Error> {
+    let message = sqlx::query_as!(
+        RejectedMessage,
+        r#"
+        SELECT rm.id, rm.payload, rm.reason, rm.topic, rm.unique_identifier, rm.created_at,
+            rm.replayed_at
+        FROM rejected_message rm
+        JOIN device d ON d.unique_identifier = rm.unique_identifier
+        WHERE rm.id = $1 AND d.owner_id = $2
+        "#,
+        id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(message)
+}
+
+// Mark a rejected message as successfully replayed
+pub async fn mark_replayed(db: &Pool<Postgres>, id: i32) -> Result<RejectedMessage, sqlx::Error> {
+    let message = sqlx::query_as!(
+        RejectedMessage,
+        r#"
+        UPDATE rejected_message
+        SET replayed_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        RETURNING id, payload, reason, topic, unique_identifier, created_at, replayed_at
+        "#,
+        id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(message)
+}
+
+// Record why the latest replay attempt failed
+pub async fn update_reason(db: &Pool<Postgres>, id: i32, reason: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE rejected_message SET reason = $1 WHERE id = $2",
+        reason,
+        id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
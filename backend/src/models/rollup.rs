@@ -0,0 +1,254 @@
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use sqlx::{Pool, Postgres};
+
+use crate::core::config::env_or;
+
+// How long each tier is kept, None to keep it forever
+pub struct Retention {
+    pub raw: Option<Duration>,
+    pub hourly: Option<Duration>,
+    pub daily: Option<Duration>,
+}
+
+impl Retention {
+    pub fn from_env() -> Self {
+        // Everything is kept unless a retention is configured
+        let days = |key: &str| {
+            let days: i64 = env_or(key, 0);
+            (days > 0).then(|| Duration::days(days))
+        };
+
+        Self {
+            raw: days("RETENTION_RAW_DAYS"),
+            hourly: days("RETENTION_HOURLY_DAYS"),
+            daily: days("RETENTION_DAILY_DAYS"),
+        }
+    }
+
+    // Start of the oldest UTC day a tier still keeps. Whole days, so a daily
+    // rollup never overlaps a finer tier.
+    pub fn cutoff(retention: Option<Duration>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        retention.map(|retention| (now - retention).duration_trunc(Duration::days(1)).unwrap())
+    }
+}
+
+// Where aggregate queries switch between tiers. Raw readings are read from
+// `raw_from`, hourly rollups between `hourly_from` and `raw_from`, and daily
+// rollups before `hourly_from`. None stands for the beginning of time.
+// Readings that are not in the rollups yet, as they arrived late or were
+// imported, are read raw whatever their time.
+pub struct Tiers {
+    pub raw_from: Option<DateTime<Utc>>,
+    pub hourly_from: Option<DateTime<Utc>>,
+}
+
+// Pick the tiers for buckets of the given length. Buckets of an hour or more read
+// the hourly rollups wherever they are complete, shorter ones read raw readings
+// as long as they are kept.
+pub async fn get_tiers(db: &Pool<Postgres>, bucket_seconds: i64) -> Result<Tiers, sqlx::Error> {
+    let rolled_until = sqlx::query_scalar!("SELECT rolled_until FROM rollup_state")
+        .fetch_one(db)
+        .await?;
+
+    // Nothing is rolled up yet, so everything is still raw
+    let Some(rolled_until) = rolled_until else {
+        return Ok(Tiers {
+            raw_from: None,
+            hourly_from: None,
+        });
+    };
+
+    let retention = Retention::from_env();
+    let now = Utc::now();
+    let raw_from = if bucket_seconds >= 60 * 60 {
+        Some(rolled_until)
+    } else {
+        Retention::cutoff(retention.raw, now).map(|cutoff| cutoff.min(rolled_until))
+    };
+
+    Ok(Tiers {
+        raw_from,
+        hourly_from: Retention::cutoff(retention.hourly, now),
+    })
+}
+
+// Newest reading id, and the transactions that may still commit readings with
+// smaller ids
+pub struct EntryBound {
+    pub max_id: i32,
+    pub writers: Vec<String>,
+}
+
+// Get the newest reading id and the transactions writing to data_entry. Writers
+// lock the table before they draw an id, so any reading that is not visible yet
+// but has a smaller id belongs to one of them.
+pub async fn get_entry_bound(db: &Pool<Postgres>) -> Result<EntryBound, sqlx::Error> {
+    sqlx::query_as!(
+        EntryBound,
+        r#"
+            SELECT
+                COALESCE((SELECT MAX(id) FROM data_entry), 0) as "max_id!",
+                ARRAY(
+                    SELECT DISTINCT virtualtransaction
+                    FROM pg_locks
+                    WHERE relation = 'data_entry'::regclass
+                    AND mode = 'RowExclusiveLock'
+                    AND pid <> pg_backend_pid()
+                ) as "writers!"
+        "#
+    )
+    .fetch_one(db)
+    .await
+}
+
+// Whether any of the given transactions is still running
+pub async fn any_running(
+    db: &Pool<Postgres>,
+    transactions: &[String],
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM pg_locks WHERE virtualtransaction = ANY($1)) as "running!""#,
+        transactions
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn get_last_entry_id(db: &Pool<Postgres>) -> Result<i32, sqlx::Error> {
+    sqlx::query_scalar!("SELECT last_entry_id FROM rollup_state")
+        .fetch_one(db)
+        .await
+}
+
+// Add the readings with ids in (from_id, to_id] to the hourly and daily rollups
+pub async fn roll_up(db: &Pool<Postgres>, from_id: i32, to_id: i32) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO reading_rollup_hourly AS r (unique_identifier, bucket, name, unit, min, max, sum, count)
+            SELECT
+                de.unique_identifier,
+                date_trunc('hour', de.created_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC',
+                dem.name,
+                MAX(dem.unit),
+                MIN(dem.value),
+                MAX(dem.value),
+                SUM(dem.value),
+                COUNT(*)
+            FROM data_entry de
+            JOIN data_entry_metric dem ON dem.data_entry_id = de.id
+            WHERE de.id > $1 AND de.id <= $2
+            GROUP BY 1, 2, 3
+            ON CONFLICT (unique_identifier, bucket, name) DO UPDATE SET
+                unit = COALESCE(EXCLUDED.unit, r.unit),
+                min = LEAST(r.min, EXCLUDED.min),
+                max = GREATEST(r.max, EXCLUDED.max),
+                sum = r.sum + EXCLUDED.sum,
+                count = r.count + EXCLUDED.count
+        "#,
+        from_id,
+        to_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO reading_rollup_daily AS r (unique_identifier, bucket, name, unit, min, max, sum, count)
+            SELECT
+                de.unique_identifier,
+                date_trunc('day', de.created_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC',
+                dem.name,
+                MAX(dem.unit),
+                MIN(dem.value),
+                MAX(dem.value),
+                SUM(dem.value),
+                COUNT(*)
+            FROM data_entry de
+            JOIN data_entry_metric dem ON dem.data_entry_id = de.id
+            WHERE de.id > $1 AND de.id <= $2
+            GROUP BY 1, 2, 3
+            ON CONFLICT (unique_identifier, bucket, name) DO UPDATE SET
+                unit = COALESCE(EXCLUDED.unit, r.unit),
+                min = LEAST(r.min, EXCLUDED.min),
+                max = GREATEST(r.max, EXCLUDED.max),
+                sum = r.sum + EXCLUDED.sum,
+                count = r.count + EXCLUDED.count
+        "#,
+        from_id,
+        to_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("UPDATE rollup_state SET last_entry_id = $1", to_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+// Record that hours before the given time are completely rolled up
+pub async fn set_rolled_until(
+    db: &Pool<Postgres>,
+    rolled_until: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE rollup_state SET rolled_until = $1", rolled_until)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+// Delete up to `limit` raw readings older than the cutoff that are already rolled up
+pub async fn delete_raw_before(
+    db: &Pool<Postgres>,
+    cutoff: DateTime<Utc>,
+    limit: i64,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+            DELETE FROM data_entry
+            WHERE id IN (
+                SELECT id FROM data_entry
+                WHERE created_at < $1
+                AND id <= (SELECT last_entry_id FROM rollup_state)
+                LIMIT $2
+            )
+        "#,
+        cutoff,
+        limit
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn delete_hourly_before(
+    db: &Pool<Postgres>,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM reading_rollup_hourly WHERE bucket < $1",
+        cutoff
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn delete_daily_before(
+    db: &Pool<Postgres>,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM reading_rollup_daily WHERE bucket < $1", cutoff)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,315 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::core::config::env_or;
+use crate::core::psychrometrics::condensation_risk;
+use crate::models::data_entry::{Bucket, DEW_POINT, Metric, Round, get_metrics_for_entries};
+use crate::models::weather;
+
+// Whether a sensor is reporting as often as expected
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorStatus {
+    Online,
+    // Missed a few readings
+    Stale,
+    // Not heard from for a long time, or never
+    Offline,
+}
+
+// How long a sensor may stay silent before it counts as stale or offline
+pub struct Staleness {
+    pub stale_after: Duration,
+    pub offline_after: Duration,
+}
+
+// How often sensors are expected to report
+pub fn expected_interval() -> Duration {
+    // Sensors report once a minute by default
+    Duration::seconds(env_or("SENSOR_EXPECTED_INTERVAL_SECS", 60i64).max(1))
+}
+
+impl Staleness {
+    pub fn from_env() -> Self {
+        Self::for_interval(expected_interval())
+    }
+
+    // Staleness of sensors reporting at another interval, like hourly weather
+    pub fn for_interval(interval: Duration) -> Self {
+        let interval = interval.num_seconds();
+        let stale_after: i64 = env_or("SENSOR_STALE_AFTER_INTERVALS", 3);
+        let offline_after: i64 = env_or("SENSOR_OFFLINE_AFTER_INTERVALS", 15);
+
+        Self {
+            stale_after: Duration::seconds(interval * stale_after),
+            offline_after: Duration::seconds(interval * offline_after),
+        }
+    }
+
+    pub fn status(&self, last_seen: Option<DateTime<Utc>>, now: DateTime<Utc>) -> SensorStatus {
+        match last_seen.map(|last_seen| now - last_seen) {
+            Some(age) if age <= self.stale_after => SensorStatus::Online,
+            Some(age) if age <= self.offline_after => SensorStatus::Stale,
+            _ => SensorStatus::Offline,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LatestReading {
+    pub mapping_id: i32,
+    pub unique_identifier: String,
+    pub label: String,
+    // Time of the newest reading, None when the sensor never reported
+    pub created_at: Option<DateTime<Utc>>,
+    pub age_seconds: Option<i64>,
+    pub status: SensorStatus,
+    pub metrics: Vec<Metric>,
+    // Whether the dew point is at or above the surface temperature of the room,
+    // None when either is unknown
+    pub condensation_risk: Option<bool>,
+}
+
+// Get the newest reading of each sensor the user has mapped
+pub async fn get_latest_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+) -> Result<Vec<LatestReading>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+            SELECT
+                dem.id as mapping_id,
+                dem.unique_identifier,
+                dem.label,
+                dem.surface_temperature,
+                latest.id as "entry_id?",
+                latest.created_at as "created_at?"
+            FROM data_entry_mapping dem
+            LEFT JOIN LATERAL (
+                SELECT de.id, de.created_at
+                FROM data_entry de
+                WHERE de.unique_identifier = dem.unique_identifier
+                ORDER BY de.created_at DESC
+                LIMIT 1
+            ) latest ON true
+            WHERE dem.user_id = $1
+            ORDER BY dem.label
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    let entry_ids: Vec<i32> = rows.iter().filter_map(|row| row.entry_id).collect();
+    let mut metrics_map = get_metrics_for_entries(db, &entry_ids).await;
+
+    let staleness = Staleness::from_env();
+    let weather_staleness = Staleness::for_interval(Duration::hours(1));
+    let now = Utc::now();
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let metrics = row
+                .entry_id
+                .and_then(|id| metrics_map.remove(&id))
+                .unwrap_or_default();
+            let status = if weather::is_virtual(&row.unique_identifier) {
+                weather_staleness.status(row.created_at, now)
+            } else {
+                staleness.status(row.created_at, now)
+            };
+            LatestReading {
+                mapping_id: row.mapping_id,
+                unique_identifier: row.unique_identifier,
+                label: row.label,
+                created_at: row.created_at,
+                age_seconds: row
+                    .created_at
+                    .map(|created_at| (now - created_at).num_seconds()),
+                status,
+                condensation_risk: condensation_risk(
+                    metrics
+                        .iter()
+                        .find(|metric| metric.name == DEW_POINT)
+                        .map(|metric| metric.value),
+                    row.surface_temperature,
+                ),
+                metrics,
+            }
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+pub struct CompletenessQuery {
+    pub from: DateTime<Utc>,
+    // Defaults to now
+    pub to: Option<DateTime<Utc>>,
+    // Defaults to days
+    pub bucket: Option<Bucket>,
+    // Shorter silences are not listed as gaps, defaults to 10
+    pub min_gap_minutes: Option<i64>,
+    // IANA timezone overriding the user's own
+    pub tz: Option<String>,
+}
+
+// A stretch without readings, between two readings or the ends of the range
+#[derive(Serialize)]
+pub struct Gap {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub minutes: i64,
+}
+
+#[derive(Serialize)]
+pub struct BucketCompleteness {
+    pub time: DateTime<Utc>,
+    pub readings: i64,
+    // Readings the sensor should have sent at the expected interval
+    pub expected: i64,
+    // Share of the expected readings that arrived, in percent
+    pub completeness: f64,
+}
+
+#[derive(Serialize)]
+pub struct CompletenessReport {
+    pub unique_identifier: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub timezone: String,
+    pub expected_interval_seconds: i64,
+    pub completeness: f64,
+    pub gaps: Vec<Gap>,
+    pub buckets: Vec<BucketCompleteness>,
+}
+
+fn completeness(readings: i64, expected: i64) -> f64 {
+    if expected == 0 {
+        return 100.0;
+    }
+    (readings as f64 / expected as f64 * 100.0)
+        .min(100.0)
+        .to_2_decimal()
+}
+
+// Find the gaps in a sensor's readings and how complete each bucket is,
+// compared to the expected interval. Readings past the raw retention are
+// gone, so the range starts no earlier than that.
+pub async fn get_completeness(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    query: &CompletenessQuery,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    timezone: &str,
+    max_gaps: i64,
+) -> Result<CompletenessReport, sqlx::Error> {
+    let interval = expected_interval();
+    let bucket_seconds = query
+        .bucket
+        .unwrap_or(Bucket::Day)
+        .seconds()
+        .unwrap_or(24 * 60 * 60);
+    let min_gap = Duration::minutes(query.min_gap_minutes.unwrap_or(10).max(1));
+    // Day and week buckets follow the timezone, shorter ones are the same everywhere
+    let bucket_timezone = if bucket_seconds >= 24 * 60 * 60 {
+        timezone
+    } else {
+        "UTC"
+    };
+
+    let gaps = sqlx::query!(
+        r#"
+            WITH times AS (
+                SELECT $2::timestamptz as time
+                UNION ALL
+                SELECT created_at FROM data_entry
+                WHERE unique_identifier = $1 AND created_at >= $2 AND created_at < $3
+                UNION ALL
+                SELECT $3::timestamptz
+            )
+            SELECT previous as "from!", time as "to!"
+            FROM (SELECT time, LAG(time) OVER (ORDER BY time) as previous FROM times) t
+            WHERE time - previous > make_interval(secs => $4)
+            ORDER BY previous
+            LIMIT $5
+        "#,
+        unique_identifier,
+        from,
+        to,
+        min_gap.num_seconds() as f64,
+        max_gaps
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| Gap {
+        from: row.from,
+        to: row.to,
+        minutes: (row.to - row.from).num_minutes(),
+    })
+    .collect();
+
+    // Buckets at the ends of the range only expect readings for the part inside it
+    let buckets: Vec<BucketCompleteness> = sqlx::query!(
+        r#"
+            WITH buckets AS (
+                SELECT local AT TIME ZONE $5 as start,
+                    (local + make_interval(secs => $4)) AT TIME ZONE $5 as finish
+                FROM generate_series(
+                    date_bin(make_interval(secs => $4), $2 AT TIME ZONE $5, TIMESTAMP '2000-01-03 00:00:00'),
+                    $3 AT TIME ZONE $5,
+                    make_interval(secs => $4)
+                ) local
+            )
+            SELECT
+                b.start as "time!",
+                EXTRACT(EPOCH FROM LEAST(b.finish, $3) - GREATEST(b.start, $2))::float8 as "covered_seconds!",
+                (
+                    SELECT COUNT(*) FROM data_entry de
+                    WHERE de.unique_identifier = $1
+                    AND de.created_at >= GREATEST(b.start, $2) AND de.created_at < LEAST(b.finish, $3)
+                ) as "readings!"
+            FROM buckets b
+            WHERE b.finish > $2 AND b.start < $3
+            ORDER BY b.start
+        "#,
+        unique_identifier,
+        from,
+        to,
+        bucket_seconds as f64,
+        bucket_timezone
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| {
+        let expected = (row.covered_seconds / interval.num_seconds() as f64).round() as i64;
+        BucketCompleteness {
+            time: row.time,
+            readings: row.readings,
+            expected,
+            completeness: completeness(row.readings, expected),
+        }
+    })
+    .collect();
+
+    Ok(CompletenessReport {
+        unique_identifier: unique_identifier.to_string(),
+        from,
+        to,
+        timezone: timezone.to_string(),
+        expected_interval_seconds: interval.num_seconds(),
+        completeness: completeness(
+            buckets
+                .iter()
+                .map(|bucket| bucket.readings.min(bucket.expected))
+                .sum(),
+            buckets.iter().map(|bucket| bucket.expected).sum(),
+        ),
+        gaps,
+        buckets,
+    })
+}
@@ -0,0 +1,72 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::models::data_entry::{ABSOLUTE_HUMIDITY, HUMIDITY, Metric, Round, TEMPERATURE};
+use crate::models::weather::ForecastHour;
+
+// Whether opening the window of a room helps now
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VentilationAdvice {
+    // Outdoor air is drier, airing out lowers the humidity inside
+    Open,
+    // Humidity is fine, or outdoor air would bring in more moisture
+    KeepClosed,
+    // Outdoor air is forecast to get dry enough later
+    Wait,
+    // No recent indoor reading or outdoor weather to go by
+    Unknown,
+}
+
+// Temperature and humidity somewhere at a time
+#[derive(Serialize, Clone, Debug)]
+pub struct Climate {
+    pub time: DateTime<Utc>,
+    pub temperature: f64,
+    pub humidity: f64,
+    pub absolute_humidity: f64,
+}
+
+impl Climate {
+    // None when the reading lacks temperature, humidity or absolute humidity
+    pub fn from_metrics(time: DateTime<Utc>, metrics: &[Metric]) -> Option<Self> {
+        let find = |name: &str| {
+            metrics
+                .iter()
+                .find(|metric| metric.name == name)
+                .map(|metric| metric.value.to_2_decimal())
+        };
+        Some(Self {
+            time,
+            temperature: find(TEMPERATURE)?,
+            humidity: find(HUMIDITY)?,
+            absolute_humidity: find(ABSOLUTE_HUMIDITY)?,
+        })
+    }
+}
+
+impl From<&ForecastHour> for Climate {
+    fn from(hour: &ForecastHour) -> Self {
+        Self {
+            time: hour.time,
+            temperature: hour.temperature,
+            humidity: hour.humidity,
+            absolute_humidity: hour.absolute_humidity,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Recommendation {
+    pub mapping_id: i32,
+    pub unique_identifier: String,
+    pub label: String,
+    pub advice: VentilationAdvice,
+    pub reason: String,
+    pub indoor: Option<Climate>,
+    pub outdoor: Option<Climate>,
+    // Indoor minus outdoor absolute humidity in g/m³, positive when outdoor air is drier
+    pub difference: Option<f64>,
+    // Driest hour ahead when waiting is advised
+    pub best_time: Option<DateTime<Utc>>,
+}
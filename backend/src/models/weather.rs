@@ -0,0 +1,304 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::models::alert;
+
+// Identifiers of virtual sensors holding outdoor weather start with this
+pub const WEATHER_PREFIX: &str = "weather:";
+
+// Label of the virtual sensor when the user does not pick one
+pub const DEFAULT_LABEL: &str = "Outdoor";
+
+// Identifier of the virtual sensor at a place, with coordinates rounded to about
+// a kilometre so users close to each other share it
+pub fn location_identifier(latitude: f64, longitude: f64) -> String {
+    // Adding zero turns -0.0 into 0.0
+    let round = |degrees: f64| (degrees * 100.0).round() / 100.0 + 0.0;
+    format!(
+        "{}{:.2}:{:.2}",
+        WEATHER_PREFIX,
+        round(latitude),
+        round(longitude)
+    )
+}
+
+pub fn is_virtual(unique_identifier: &str) -> bool {
+    unique_identifier.starts_with(WEATHER_PREFIX)
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct WeatherLocation {
+    pub unique_identifier: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    // When weather was last fetched or tried, None before the first try
+    pub fetched_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SetLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    // Label of the virtual sensor, defaults to "Outdoor"
+    pub label: Option<String>,
+}
+
+// Outdoor temperature and humidity at an hour, measured or forecast
+#[derive(Clone, Debug)]
+pub struct WeatherHour {
+    pub time: DateTime<Utc>,
+    pub temperature: f64,
+    pub humidity: f64,
+}
+
+#[derive(Serialize)]
+pub struct ForecastHour {
+    pub time: DateTime<Utc>,
+    pub temperature: f64,
+    pub humidity: f64,
+    pub dew_point: f64,
+    pub absolute_humidity: f64,
+}
+
+#[derive(Serialize)]
+pub struct WeatherResponse {
+    pub location: WeatherLocation,
+    pub forecast: Vec<ForecastHour>,
+}
+
+pub async fn get_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+) -> Result<Option<WeatherLocation>, sqlx::Error> {
+    sqlx::query_as!(
+        WeatherLocation,
+        r#"
+        SELECT wl.unique_identifier, wl.latitude, wl.longitude, wl.fetched_at, wl.last_error
+        FROM user_location ul
+        JOIN weather_location wl ON wl.unique_identifier = ul.unique_identifier
+        WHERE ul.user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+// Set where a user is and map the virtual sensor there for them, in place of
+// the one at their previous location
+pub async fn set_for_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    location: SetLocation,
+) -> Result<WeatherLocation, sqlx::Error> {
+    let unique_identifier = location_identifier(location.latitude, location.longitude);
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO weather_location (unique_identifier, latitude, longitude)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (unique_identifier) DO NOTHING
+        "#,
+        unique_identifier,
+        location.latitude,
+        location.longitude
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let previous = sqlx::query_scalar!(
+        "SELECT unique_identifier FROM user_location WHERE user_id = $1 FOR UPDATE",
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(previous) = previous.filter(|previous| *previous != unique_identifier) {
+        sqlx::query!(
+            "DELETE FROM data_entry_mapping WHERE unique_identifier = $1 AND user_id = $2",
+            previous,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        alert::delete_unmapped(&mut tx, &previous).await?;
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_location (user_id, unique_identifier)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE
+        SET unique_identifier = EXCLUDED.unique_identifier, updated_at = CURRENT_TIMESTAMP
+        "#,
+        user_id,
+        unique_identifier
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO data_entry_mapping (unique_identifier, label, user_id)
+        VALUES ($1, COALESCE($2, $3), $4)
+        ON CONFLICT ON CONSTRAINT unique_user_identifier DO UPDATE
+        SET label = COALESCE($2, data_entry_mapping.label)
+        "#,
+        unique_identifier,
+        location.label,
+        DEFAULT_LABEL,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let location = sqlx::query_as!(
+        WeatherLocation,
+        r#"
+        SELECT unique_identifier, latitude, longitude, fetched_at, last_error
+        FROM weather_location
+        WHERE unique_identifier = $1
+        "#,
+        unique_identifier
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(location)
+}
+
+// Forget where a user is and unmap their virtual sensor along with their alert
+// rules on it. Its readings are kept.
+pub async fn delete_for_user(db: &Pool<Postgres>, user_id: i32) -> Result<bool, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let deleted = sqlx::query_scalar!(
+        "DELETE FROM user_location WHERE user_id = $1 RETURNING unique_identifier",
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(unique_identifier) = deleted else {
+        return Ok(false);
+    };
+
+    sqlx::query!(
+        "DELETE FROM data_entry_mapping WHERE unique_identifier = $1 AND user_id = $2",
+        unique_identifier,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    alert::delete_unmapped(&mut tx, &unique_identifier).await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+// Get the locations someone is at that were not fetched within `max_age_seconds`
+pub async fn get_due(
+    db: &Pool<Postgres>,
+    max_age_seconds: f64,
+) -> Result<Vec<WeatherLocation>, sqlx::Error> {
+    sqlx::query_as!(
+        WeatherLocation,
+        r#"
+        SELECT unique_identifier, latitude, longitude, fetched_at, last_error
+        FROM weather_location wl
+        WHERE EXISTS (SELECT 1 FROM user_location ul WHERE ul.unique_identifier = wl.unique_identifier)
+        AND (fetched_at IS NULL OR fetched_at < now() - make_interval(secs => $1))
+        ORDER BY unique_identifier
+        "#,
+        max_age_seconds
+    )
+    .fetch_all(db)
+    .await
+}
+
+// Replace the forecast of a location with a freshly fetched one
+pub async fn save_forecast(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    hours: &[WeatherHour],
+    fetched_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        "DELETE FROM weather_forecast WHERE unique_identifier = $1",
+        unique_identifier
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let times: Vec<DateTime<Utc>> = hours.iter().map(|hour| hour.time).collect();
+    let temperatures: Vec<f64> = hours.iter().map(|hour| hour.temperature).collect();
+    let humidities: Vec<f64> = hours.iter().map(|hour| hour.humidity).collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO weather_forecast (unique_identifier, time, temperature, humidity)
+        SELECT $1, * FROM UNNEST($2::timestamptz[], $3::float8[], $4::float8[])
+        "#,
+        unique_identifier,
+        &times,
+        &temperatures,
+        &humidities
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE weather_location SET fetched_at = $2, last_error = NULL WHERE unique_identifier = $1",
+        unique_identifier,
+        fetched_at
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
+
+// Record a failed fetch. It is tried again once the location is due.
+pub async fn save_error(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    error: &str,
+    fetched_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE weather_location SET fetched_at = $2, last_error = $3 WHERE unique_identifier = $1",
+        unique_identifier,
+        fetched_at,
+        error
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+// Get the forecast of a location from `from` on
+pub async fn get_forecast(
+    db: &Pool<Postgres>,
+    unique_identifier: &str,
+    from: DateTime<Utc>,
+) -> Result<Vec<WeatherHour>, sqlx::Error> {
+    sqlx::query_as!(
+        WeatherHour,
+        r#"
+        SELECT time, temperature, humidity
+        FROM weather_forecast
+        WHERE unique_identifier = $1 AND time >= $2
+        ORDER BY time
+        "#,
+        unique_identifier,
+        from
+    )
+    .fetch_all(db)
+    .await
+}
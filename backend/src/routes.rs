@@ -1,18 +1,50 @@
+use crate::core::config::env_or;
+use crate::core::export::{self, Export};
+use crate::core::import::{ImportFormat, ImportSummary, Importer};
+use crate::core::ingest::{self, IngestError, IngestItemResult, IngestStatus};
+use crate::core::message_queue::{ConnectionState, SharedConsumerStatus};
+use crate::core::notify;
+use crate::core::smtp;
+use crate::core::stream::{LiveReadings, Subscription};
+use crate::core::ventilation;
+use crate::core::weather as core_weather;
 use crate::middleware::auth::auth_middleware;
+use crate::models::alert::{
+    self, AlertCondition, AlertEvent, AlertHistoryQuery, AlertRule, CreateAlertRule,
+    UpdateAlertRule,
+};
 use crate::models::app_user::{
     Claims, CreateAppUser, LoginCredentials, LoginResponse, UpdateAppUser, UserResponse,
-    create as create_user, login, update as update_user,
+    create as create_user, get_by_id as get_user_by_id, get_by_username, is_valid_timezone, login,
+    update as update_user,
 };
 use crate::models::data_entry::{
-    AverageQuery, AverageResponse, CountResponse, DataEntry, LimitQuery,
-    get_daily_averages_for_user, get_public_count_data, get_recent_entries_for_user
+    AverageQuery, AverageResponse, Bucket, CountResponse, DataEntry, ExportQuery, LimitQuery,
+    ReadingsQuery, ReadingsResponse, Round, get_daily_averages_for_user, get_public_count_data,
+    get_readings_for_user, get_recent_entries_for_user,
 };
 use crate::models::data_entry_mapping::{
     CreateDataEntryMapping, DataEntryMapping, UpdateDataEntryMapping, create,
-    delete as delete_mapping, get_all_for_user, get_by_id_for_user, update,
+    delete as delete_mapping, get_all_for_user, get_by_id_for_user, is_mapped, update,
+};
+use crate::models::device::{self, ClaimDevice, ClaimResult, Device, TransferDevice};
+use crate::models::mold::{self, MoldRiskLevel, MoldRiskQuery, MoldRiskResponse};
+use crate::models::notification::{
+    self, ChannelConfig, CreateNotificationChannel, DeliveryQuery, Notification,
+    NotificationChannel, NotificationDelivery, RuleChannels, UpdateNotificationChannel,
 };
-use axum::http::StatusCode;
+use crate::models::rejected_message::{self, RejectedMessage, RejectedMessageQuery};
+use crate::models::rollup::Retention;
+use crate::models::sensor::{self, CompletenessQuery, CompletenessReport, LatestReading};
+use crate::models::ventilation::Recommendation;
+use crate::models::weather::{self, SetLocation, WeatherLocation, WeatherResponse};
+use axum::body::Body;
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::{Message, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode, header};
 use axum::middleware;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::{
     Extension, Json, Router,
     extract::Path,
@@ -20,6 +52,8 @@ use axum::{
     extract::State,
     routing::{delete, get, post, put},
 };
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
 use sqlx::Pool;
 use sqlx::Postgres;
 use tower_http::cors::{Any, CorsLayer};
@@ -27,6 +61,8 @@ use tower_http::cors::{Any, CorsLayer};
 #[derive(Clone)]
 pub struct AppState {
     pub db: Pool<Postgres>,
+    pub consumer_status: SharedConsumerStatus,
+    pub live: LiveReadings,
 }
 
 // Public endpoint - shows overall system stats without requiring authentication
@@ -35,6 +71,34 @@ pub async fn root(State(state): State<AppState>) -> Json<CountResponse> {
     Json(response)
 }
 
+// Public endpoint - reports whether the database and ingestion are up
+pub async fn health(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    let database_up = sqlx::query!("SELECT 1 as one")
+        .fetch_one(&state.db)
+        .await
+        .is_ok();
+    let consumer_status = state.consumer_status.read().unwrap().clone();
+    let healthy = database_up && consumer_status.state == ConnectionState::Connected;
+
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "status": if healthy { "ok" } else { "degraded" },
+            "database": if database_up { "up" } else { "down" },
+            "message_queue": consumer_status,
+            "ingest": {
+                "unauthenticated_readings": ingest::unauthenticated_readings(),
+            },
+        })),
+    )
+}
+
 // Protected endpoint - shows entries for authenticated user
 pub async fn get_entries(
     State(state): State<AppState>,
@@ -46,21 +110,520 @@ pub async fn get_entries(
     Json(entries)
 }
 
+// Protected endpoint - shows the newest reading and status of each of the user's sensors
+pub async fn get_latest_readings(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<LatestReading>>, (StatusCode, String)> {
+    match sensor::get_latest_for_user(&state.db, claims.user_id).await {
+        Ok(readings) => Ok(Json(readings)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - lists the gaps in a sensor's readings and how complete
+// each bucket is, to tell how far its averages can be trusted
+pub async fn get_sensor_completeness(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(unique_identifier): Path<String>,
+    Query(query): Query<CompletenessQuery>,
+) -> Result<Json<CompletenessReport>, (StatusCode, String)> {
+    require_mapping(&state, &unique_identifier, claims.user_id).await?;
+
+    // Raw readings are gone past their retention, and nothing is expected from the future
+    let now = chrono::Utc::now();
+    let from = match Retention::cutoff(Retention::from_env().raw, now) {
+        Some(cutoff) => query.from.max(cutoff),
+        None => query.from,
+    };
+    let to = query.to.unwrap_or(now).min(now);
+    if from >= to {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'from' must be before 'to'".to_string(),
+        ));
+    }
+
+    let max_points: i64 = env_or("READINGS_MAX_POINTS", 10_000);
+    let Some(seconds) = query.bucket.unwrap_or(Bucket::Day).seconds() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'bucket' must not be raw".to_string(),
+        ));
+    };
+    if (to - from).num_seconds() / seconds > max_points {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Range spans more than {} buckets, use a larger bucket",
+                max_points
+            ),
+        ));
+    }
+
+    let timezone = resolve_timezone(&state, claims.user_id, query.tz.as_deref()).await?;
+    match sensor::get_completeness(
+        &state.db,
+        &unique_identifier,
+        &query,
+        from,
+        to,
+        &timezone,
+        max_points,
+    )
+    .await
+    {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets the mold index of a sensor over time, computed
+// hourly from its humidity and temperature
+pub async fn get_sensor_mold_risk(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(unique_identifier): Path<String>,
+    Query(query): Query<MoldRiskQuery>,
+) -> Result<Json<MoldRiskResponse>, (StatusCode, String)> {
+    require_mapping(&state, &unique_identifier, claims.user_id).await?;
+
+    let to = query.to.unwrap_or_else(chrono::Utc::now);
+    if query.from >= to {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'from' must be before 'to'".to_string(),
+        ));
+    }
+
+    let max_points: i64 = env_or("READINGS_MAX_POINTS", 10_000);
+    let Some(seconds) = query.bucket.unwrap_or(Bucket::Day).seconds() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'bucket' must not be raw".to_string(),
+        ));
+    };
+    if (to - query.from).num_seconds() / seconds > max_points {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Range spans more than {} buckets, use a larger bucket",
+                max_points
+            ),
+        ));
+    }
+
+    let timezone = resolve_timezone(&state, claims.user_id, query.tz.as_deref()).await?;
+    let points = mold::get_series(
+        &state.db,
+        &unique_identifier,
+        query.from,
+        to,
+        seconds,
+        &timezone,
+    )
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let index = mold::get_state(&state.db, &unique_identifier)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(|state| state.mold_index.to_2_decimal());
+
+    Ok(Json(MoldRiskResponse {
+        unique_identifier,
+        from: query.from,
+        to,
+        timezone,
+        index,
+        level: index.map(MoldRiskLevel::from_index),
+        points,
+    }))
+}
+
+// Protected endpoint - gets the user's location and the outdoor forecast there.
+// Measured outdoor weather is stored as readings of the location's virtual sensor.
+pub async fn get_weather(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<WeatherResponse>, (StatusCode, String)> {
+    let location = weather::get_for_user(&state.db, claims.user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "No location set".to_string()))?;
+
+    let now = chrono::Utc::now();
+    let forecast = weather::get_forecast(&state.db, &location.unique_identifier, now)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(WeatherResponse {
+        location,
+        forecast: core_weather::forecast_hours(forecast),
+    }))
+}
+
+// Protected endpoint - sets where the user is, mapping the virtual sensor with
+// outdoor weather there for them
+pub async fn set_weather_location(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<SetLocation>,
+) -> Result<Json<WeatherLocation>, (StatusCode, String)> {
+    if !(-90.0..=90.0).contains(&payload.latitude) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'latitude' must be between -90 and 90".to_string(),
+        ));
+    }
+    if !(-180.0..=180.0).contains(&payload.longitude) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'longitude' must be between -180 and 180".to_string(),
+        ));
+    }
+    if payload
+        .label
+        .as_ref()
+        .is_some_and(|label| label.trim().is_empty())
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'label' must not be empty".to_string(),
+        ));
+    }
+
+    let location = weather::set_for_user(&state.db, claims.user_id, payload)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    core_weather::location_set();
+
+    Ok(Json(location))
+}
+
+// Protected endpoint - forgets where the user is and unmaps their virtual sensor
+pub async fn delete_weather_location(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match weather::delete_for_user(&state.db, claims.user_id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, "No location set".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets whether to open the window of each of the user's
+// rooms, comparing the absolute humidity inside with the weather outside
+pub async fn get_ventilation(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<Recommendation>>, (StatusCode, String)> {
+    match ventilation::get_for_user(&state.db, claims.user_id).await {
+        Ok(recommendations) => Ok(Json(recommendations)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    // Comma-separated identifiers, all mapped sensors when unset
+    pub unique_identifiers: Option<String>,
+}
+
+// Protected endpoint - streams new readings of the user's sensors as they are
+// stored, over WebSocket when the client asks for an upgrade and SSE otherwise
+pub async fn stream_readings(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<StreamQuery>,
+    // Rejected for plain HTTP requests, which get SSE instead
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<Response, (StatusCode, String)> {
+    let filter = query.unique_identifiers.map(|identifiers| {
+        identifiers
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .collect()
+    });
+    let mut subscription = Subscription::new(&state.db, &state.live, claims.user_id, filter)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if let Ok(ws) = ws {
+        return Ok(ws.on_upgrade(move |mut socket| async move {
+            loop {
+                tokio::select! {
+                    entry = subscription.next() => {
+                        let Some(entry) = entry else { break };
+                        let message = Message::Text(serde_json::to_string(&entry).unwrap().into());
+                        if socket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Anything but a ping from the client ends the stream
+                    message = socket.recv() => match message {
+                        Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                        _ => break,
+                    },
+                }
+            }
+        }));
+    }
+
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        let entry = subscription.next().await?;
+        let event = Event::default().event("reading").json_data(&entry);
+        Some((event, subscription))
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+// Reject timezone names the database does not know
+async fn validate_timezone(state: &AppState, timezone: &str) -> Result<(), (StatusCode, String)> {
+    match is_valid_timezone(&state.db, timezone).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Unknown timezone: {}", timezone),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Timezone to aggregate in: the `tz` override if given, otherwise the user's own
+async fn resolve_timezone(
+    state: &AppState,
+    user_id: i32,
+    tz: Option<&str>,
+) -> Result<String, (StatusCode, String)> {
+    if let Some(tz) = tz {
+        validate_timezone(state, tz).await?;
+        return Ok(tz.to_string());
+    }
+
+    match get_user_by_id(&state.db, user_id).await {
+        Ok(Some(user)) => Ok(user.timezone),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "User not found".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
 // Protected endpoint - shows averages for authenticated user
 pub async fn get_averages(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
     Query(query): Query<AverageQuery>,
-) -> Json<AverageResponse> {
+) -> Result<Json<AverageResponse>, (StatusCode, String)> {
     let days_back = query.days.unwrap_or(7);
-    let response = get_daily_averages_for_user(
+    let timezone = resolve_timezone(&state, claims.user_id, query.tz.as_deref()).await?;
+    match get_daily_averages_for_user(
         &state.db,
         claims.user_id,
         &query.unique_identifiers,
         days_back,
+        &timezone,
     )
-    .await;
-    Json(response)
+    .await
+    {
+        Ok(response) => Ok(Json(response)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Only the owner of a device may map it
+async fn require_device_owner(
+    state: &AppState,
+    unique_identifier: &str,
+    user_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    match device::is_owner(&state.db, unique_identifier, user_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::FORBIDDEN,
+            "Device must be claimed before it can be mapped".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - shows readings in a time range for authenticated user,
+// raw or aggregated into buckets
+pub async fn get_readings(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<ReadingsQuery>,
+) -> Result<Json<ReadingsResponse>, (StatusCode, String)> {
+    let to = query.to.unwrap_or_else(chrono::Utc::now);
+    if query.from >= to {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'from' must be before 'to'".to_string(),
+        ));
+    }
+
+    // Bound the response size, both up front for buckets and while reading raw rows
+    let max_points: i64 = env_or("READINGS_MAX_POINTS", 10_000);
+    let bucket = query.bucket.unwrap_or(Bucket::Raw);
+    if let Some(seconds) = bucket.seconds()
+        && (to - query.from).num_seconds() / seconds > max_points
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Range spans more than {} buckets, use a larger bucket",
+                max_points
+            ),
+        ));
+    }
+
+    let timezone = resolve_timezone(&state, claims.user_id, query.tz.as_deref()).await?;
+    let response = get_readings_for_user(
+        &state.db,
+        claims.user_id,
+        &query,
+        to,
+        max_points + 1,
+        &timezone,
+    )
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let points: usize = response
+        .identifiers
+        .values()
+        .flat_map(|buckets| buckets.iter().map(|bucket| bucket.metrics.len()))
+        .sum();
+    if points as i64 > max_points {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Range has more than {} values, use a larger bucket",
+                max_points
+            ),
+        ));
+    }
+
+    Ok(Json(response))
+}
+
+// Protected endpoint - downloads the user's readings in a time range as CSV,
+// NDJSON or Parquet, streamed while it is read
+pub async fn export_readings(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let to = query.to.unwrap_or_else(chrono::Utc::now);
+    if query.from >= to {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'from' must be before 'to'".to_string(),
+        ));
+    }
+
+    let format = query.format.unwrap_or_default();
+    let export = Export {
+        user_id: claims.user_id,
+        identifiers: query.unique_identifiers.map(|identifiers| {
+            identifiers
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .collect()
+        }),
+        from: query.from,
+        to,
+        metric_names: query.metrics.map(|metrics| {
+            metrics
+                .split(',')
+                .map(|name| name.trim().to_lowercase())
+                .collect()
+        }),
+        format,
+    };
+
+    let disposition = format!(
+        "attachment; filename=\"readings-{}-{}.{}\"",
+        query.from.format("%Y%m%d"),
+        to.format("%Y%m%d"),
+        format.extension()
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        export::body(&state.db, export),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    // Taken from the Content-Type header when unset
+    pub format: Option<ImportFormat>,
+}
+
+// Protected endpoint - imports historical readings of the user's devices from an
+// uploaded CSV or NDJSON file, read as it arrives
+pub async fn import_readings(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<ImportSummary>, (StatusCode, String)> {
+    let format = query
+        .format
+        .or_else(|| {
+            headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(ImportFormat::detect)
+        })
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            "Unknown format, pass format=csv or format=ndjson".to_string(),
+        ))?;
+
+    let allowed = device::get_all_for_owner(&state.db, claims.user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .into_iter()
+        .map(|device| device.unique_identifier)
+        .collect();
+
+    let mut importer = Importer::new(&state.db, format, allowed);
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        importer
+            .feed(&chunk)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    let summary = importer
+        .finish()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(summary))
+}
+
+// Surface temperatures are entered by hand, so only plausible ones are accepted
+fn validate_surface_temperature(
+    surface_temperature: Option<f64>,
+) -> Result<(), (StatusCode, String)> {
+    if surface_temperature.is_some_and(|temperature| !(-50.0..=60.0).contains(&temperature)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'surface_temperature' must be between -50 and 60 °C".to_string(),
+        ));
+    }
+    Ok(())
 }
 
 // Protected endpoint - creates mapping for authenticated user
@@ -69,6 +632,9 @@ pub async fn create_mapping(
     Extension(claims): Extension<Claims>,
     Json(payload): Json<CreateDataEntryMapping>,
 ) -> Result<Json<DataEntryMapping>, (StatusCode, String)> {
+    validate_surface_temperature(payload.surface_temperature)?;
+    require_device_owner(&state, &payload.unique_identifier, claims.user_id).await?;
+
     match create(&state.db, payload, claims.user_id).await {
         Ok(mapping) => Ok(Json(mapping)),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
@@ -106,6 +672,11 @@ pub async fn update_mapping(
     Path(id): Path<i32>,
     Json(payload): Json<UpdateDataEntryMapping>,
 ) -> Result<Json<DataEntryMapping>, (StatusCode, String)> {
+    validate_surface_temperature(payload.surface_temperature.flatten())?;
+    if let Some(unique_identifier) = &payload.unique_identifier {
+        require_device_owner(&state, unique_identifier, claims.user_id).await?;
+    }
+
     match update(&state.db, id, payload, claims.user_id).await {
         Ok(Some(mapping)) => Ok(Json(mapping)),
         Ok(None) => Err((
@@ -132,11 +703,626 @@ pub async fn delete_mapping_handler(
     }
 }
 
+// Protected endpoint - lists devices owned by authenticated user
+pub async fn get_devices(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<Device>>, (StatusCode, String)> {
+    match device::get_all_for_owner(&state.db, claims.user_id).await {
+        Ok(devices) => Ok(Json(devices)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - claims a device for authenticated user with its claim code
+pub async fn claim_device(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<ClaimDevice>,
+) -> Result<Json<Device>, (StatusCode, String)> {
+    match device::claim(&state.db, payload, claims.user_id).await {
+        Ok(ClaimResult::Claimed(device)) => Ok(Json(device)),
+        Ok(ClaimResult::NotFound) => Err((StatusCode::NOT_FOUND, "Device not found".to_string())),
+        Ok(ClaimResult::InvalidCode) => {
+            Err((StatusCode::FORBIDDEN, "Invalid claim code".to_string()))
+        }
+        Ok(ClaimResult::AlreadyOwned) => Err((
+            StatusCode::CONFLICT,
+            "Device is owned by another account, which has to transfer it".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - transfers a device owned by authenticated user to another account
+pub async fn transfer_device(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(unique_identifier): Path<String>,
+    Json(payload): Json<TransferDevice>,
+) -> Result<Json<Device>, (StatusCode, String)> {
+    let new_owner = match get_by_username(&state.db, &payload.username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "User not found".to_string())),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+
+    match device::transfer(&state.db, &unique_identifier, claims.user_id, new_owner.id).await {
+        Ok(Some(device)) => Ok(Json(device)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "Device not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Alert rules can only watch sensors the user has mapped
+async fn require_mapping(
+    state: &AppState,
+    unique_identifier: &str,
+    user_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    match is_mapped(&state.db, unique_identifier, user_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            format!("No mapped sensor {}", unique_identifier),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+fn validate_alert_rule(
+    hysteresis: Option<f64>,
+    durations: [(&str, Option<i32>); 3],
+) -> Result<(), (StatusCode, String)> {
+    if hysteresis.is_some_and(|hysteresis| hysteresis < 0.0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'hysteresis' must not be negative".to_string(),
+        ));
+    }
+    for (name, seconds) in durations {
+        if seconds.is_some_and(|seconds| seconds < 0) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("'{}' must not be negative", name),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Rate-of-change rules need a window to look back over
+fn validate_rate_rule(
+    condition: AlertCondition,
+    window_seconds: i32,
+) -> Result<(), (StatusCode, String)> {
+    if matches!(condition, AlertCondition::Rises | AlertCondition::Falls) && window_seconds <= 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'window_seconds' is required for rate-of-change rules".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Offline and ventilation rules are the only ones without a threshold of their own
+fn needs_threshold(condition: AlertCondition) -> bool {
+    !matches!(
+        condition,
+        AlertCondition::Offline | AlertCondition::Ventilate
+    )
+}
+
+fn validate_condition(
+    condition: AlertCondition,
+    has_threshold: bool,
+    unique_identifier: &str,
+) -> Result<(), (StatusCode, String)> {
+    if needs_threshold(condition) && !has_threshold {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'threshold' is required".to_string(),
+        ));
+    }
+    if condition == AlertCondition::Ventilate && weather::is_virtual(unique_identifier) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Ventilation rules need an indoor sensor".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Protected endpoint - creates an alert rule on one of the user's sensors
+pub async fn create_alert_rule(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CreateAlertRule>,
+) -> Result<Json<AlertRule>, (StatusCode, String)> {
+    validate_alert_rule(
+        payload.hysteresis,
+        [
+            ("duration_seconds", payload.duration_seconds),
+            ("window_seconds", payload.window_seconds),
+            ("cooldown_seconds", payload.cooldown_seconds),
+        ],
+    )?;
+    validate_rate_rule(payload.condition, payload.window_seconds.unwrap_or(0))?;
+    validate_condition(
+        payload.condition,
+        payload.threshold.is_some(),
+        &payload.unique_identifier,
+    )?;
+    require_mapping(&state, &payload.unique_identifier, claims.user_id).await?;
+
+    match alert::create(&state.db, payload, claims.user_id).await {
+        Ok(rule) => Ok(Json(rule)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets all alert rules of the user with their state
+pub async fn get_alert_rules(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<AlertRule>>, (StatusCode, String)> {
+    match alert::get_all_for_user(&state.db, claims.user_id).await {
+        Ok(rules) => Ok(Json(rules)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets a single alert rule
+pub async fn get_alert_rule(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+) -> Result<Json<AlertRule>, (StatusCode, String)> {
+    match alert::get_by_id_for_user(&state.db, id, claims.user_id).await {
+        Ok(Some(rule)) => Ok(Json(rule)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "Alert rule not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - updates an alert rule, which resets its state
+pub async fn update_alert_rule(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+    Json(payload): Json<UpdateAlertRule>,
+) -> Result<Json<AlertRule>, (StatusCode, String)> {
+    validate_alert_rule(
+        payload.hysteresis,
+        [
+            ("duration_seconds", payload.duration_seconds),
+            ("window_seconds", payload.window_seconds),
+            ("cooldown_seconds", payload.cooldown_seconds),
+        ],
+    )?;
+    // The checks of a new rule apply to the rule as it is after the update
+    match alert::get_by_id_for_user(&state.db, id, claims.user_id).await {
+        Ok(Some(rule)) => {
+            let condition = payload.condition.unwrap_or(rule.condition);
+            validate_rate_rule(
+                condition,
+                payload.window_seconds.unwrap_or(rule.window_seconds),
+            )?;
+            // The threshold of an offline or ventilation rule means nothing to others
+            validate_condition(
+                condition,
+                payload.threshold.is_some() || needs_threshold(rule.condition),
+                &rule.unique_identifier,
+            )?;
+        }
+        Ok(None) => {}
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+
+    match alert::update(&state.db, id, payload, claims.user_id).await {
+        Ok(Some(rule)) => Ok(Json(rule)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "Alert rule not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - deletes an alert rule and its history
+pub async fn delete_alert_rule(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match alert::delete(&state.db, id, claims.user_id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            "Alert rule not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets the user's alerts that are currently firing
+pub async fn get_active_alerts(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<AlertRule>>, (StatusCode, String)> {
+    match alert::get_firing_for_user(&state.db, claims.user_id).await {
+        Ok(rules) => Ok(Json(rules)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets the newest alerts that fired or resolved
+pub async fn get_alert_history(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<AlertHistoryQuery>,
+) -> Result<Json<Vec<AlertEvent>>, (StatusCode, String)> {
+    match alert::get_history_for_user(&state.db, claims.user_id, &query).await {
+        Ok(events) => Ok(Json(events)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+fn validate_channel_config(config: &ChannelConfig) -> Result<(), (StatusCode, String)> {
+    let url = match config {
+        ChannelConfig::Webhook { url, .. }
+        | ChannelConfig::Ntfy { url, .. }
+        | ChannelConfig::Gotify { url, .. } => url,
+        ChannelConfig::Email { to } => {
+            if !smtp::is_valid_address(to) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid email address: {}", to),
+                ));
+            }
+            return Ok(());
+        }
+    };
+    notify::check_target(url).map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+// Protected endpoint - creates a notification channel
+pub async fn create_notification_channel(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CreateNotificationChannel>,
+) -> Result<Json<NotificationChannel>, (StatusCode, String)> {
+    validate_channel_config(&payload.config)?;
+
+    match notification::create(&state.db, payload, claims.user_id).await {
+        Ok(channel) => Ok(Json(channel)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets all notification channels of the user
+pub async fn get_notification_channels(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<NotificationChannel>>, (StatusCode, String)> {
+    match notification::get_all_for_user(&state.db, claims.user_id).await {
+        Ok(channels) => Ok(Json(channels)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets a single notification channel
+pub async fn get_notification_channel(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+) -> Result<Json<NotificationChannel>, (StatusCode, String)> {
+    match notification::get_by_id_for_user(&state.db, id, claims.user_id).await {
+        Ok(Some(channel)) => Ok(Json(channel)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "Notification channel not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - updates a notification channel
+pub async fn update_notification_channel(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+    Json(payload): Json<UpdateNotificationChannel>,
+) -> Result<Json<NotificationChannel>, (StatusCode, String)> {
+    if let Some(config) = &payload.config {
+        validate_channel_config(config)?;
+    }
+
+    match notification::update(&state.db, id, payload, claims.user_id).await {
+        Ok(Some(channel)) => Ok(Json(channel)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "Notification channel not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - deletes a notification channel and its delivery log
+pub async fn delete_notification_channel(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match notification::delete(&state.db, id, claims.user_id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            "Notification channel not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - sends a test notification right away, without retries.
+// The delivery is logged and returned, with the error if sending failed.
+pub async fn test_notification_channel(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+) -> Result<Json<NotificationDelivery>, (StatusCode, String)> {
+    let channel = match notification::get_by_id_for_user(&state.db, id, claims.user_id).await {
+        Ok(Some(channel)) => channel,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "Notification channel not found or not authorized".to_string(),
+            ));
+        }
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+
+    let test = Notification::test(&channel.name);
+    let error = notify::send(&channel.config, &test).await.err();
+
+    match notification::create_delivery(&state.db, channel.id, &test, error.as_deref()).await {
+        Ok(delivery) => Ok(Json(delivery)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets the newest notification deliveries with their status
+pub async fn get_notification_deliveries(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<DeliveryQuery>,
+) -> Result<Json<Vec<NotificationDelivery>>, (StatusCode, String)> {
+    match notification::get_deliveries_for_user(&state.db, claims.user_id, &query).await {
+        Ok(deliveries) => Ok(Json(deliveries)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+async fn require_alert_rule(
+    state: &AppState,
+    id: i32,
+    user_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    match alert::get_by_id_for_user(&state.db, id, user_id).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "Alert rule not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets the channels an alert rule notifies
+pub async fn get_alert_rule_channels(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+) -> Result<Json<RuleChannels>, (StatusCode, String)> {
+    require_alert_rule(&state, id, claims.user_id).await?;
+
+    match notification::get_rule_channel_ids(&state.db, id).await {
+        Ok(channel_ids) => Ok(Json(RuleChannels { channel_ids })),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - replaces the channels an alert rule notifies
+pub async fn set_alert_rule_channels(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+    Json(payload): Json<RuleChannels>,
+) -> Result<Json<RuleChannels>, (StatusCode, String)> {
+    require_alert_rule(&state, id, claims.user_id).await?;
+
+    match notification::set_rule_channels(&state.db, id, &payload.channel_ids, claims.user_id).await
+    {
+        Ok(Some(channel_ids)) => Ok(Json(RuleChannels { channel_ids })),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "Notification channel not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - lists quarantined ingest messages of the user's devices
+pub async fn get_rejected_messages(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<RejectedMessageQuery>,
+) -> Result<Json<Vec<RejectedMessage>>, (StatusCode, String)> {
+    let limit = query.limit.unwrap_or(50);
+    let include_replayed = query.include_replayed.unwrap_or(false);
+
+    match rejected_message::get_all_for_owner(&state.db, claims.user_id, limit, include_replayed)
+        .await
+    {
+        Ok(messages) => Ok(Json(messages)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - gets a quarantined ingest message of one of the user's devices
+pub async fn get_rejected_message(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+) -> Result<Json<RejectedMessage>, (StatusCode, String)> {
+    match rejected_message::get_by_id_for_owner(&state.db, id, claims.user_id).await {
+        Ok(Some(message)) => Ok(Json(message)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "Rejected message not found or not authorized".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Protected endpoint - runs a quarantined message of one of the user's devices
+// through ingestion again
+pub async fn replay_rejected_message(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<i32>,
+) -> Result<Json<RejectedMessage>, (StatusCode, String)> {
+    let message = match rejected_message::get_by_id_for_owner(&state.db, id, claims.user_id).await {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "Rejected message not found or not authorized".to_string(),
+            ));
+        }
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+
+    if message.replayed_at.is_some() {
+        return Err((
+            StatusCode::CONFLICT,
+            "Message was already replayed".to_string(),
+        ));
+    }
+
+    match ingest::replay_payload(
+        &state.db,
+        &state.live,
+        &message.payload,
+        message.topic.as_deref(),
+    )
+    .await
+    {
+        Ok(_) => match rejected_message::mark_replayed(&state.db, id).await {
+            Ok(message) => Ok(Json(message)),
+            Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        },
+        Err(e) => {
+            let reason = e.to_string();
+            if let Err(e) = rejected_message::update_reason(&state.db, id, &reason).await {
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+            }
+            let status = match e {
+                IngestError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                IngestError::Unauthenticated(_) => StatusCode::FORBIDDEN,
+                IngestError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err((status, reason))
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct IngestResponse {
+    pub stored: usize,
+    pub duplicates: usize,
+    pub rejected: usize,
+    pub results: Vec<IngestItemResult>,
+}
+
+// Public endpoint - ingests a single reading or an array of readings over HTTP.
+// Devices authenticate with the X-Device-Token header, which is required even
+// while DEVICE_AUTH_REQUIRED is disabled, or a token in each reading.
+pub async fn ingest_readings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<IngestResponse>), (StatusCode, String)> {
+    let values = match payload {
+        serde_json::Value::Array(values) => values,
+        value => vec![value],
+    };
+
+    let max_batch_size: usize = env_or("INGEST_HTTP_MAX_BATCH", 1000);
+    if values.len() > max_batch_size {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} readings per request", max_batch_size),
+        ));
+    }
+
+    let Some(token) = headers
+        .get("x-device-token")
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Missing X-Device-Token header".to_string(),
+        ));
+    };
+
+    let results =
+        match ingest::store_values(&state.db, &state.live, values, None, Some(token)).await {
+            Ok(results) => results,
+            Err(e) if ingest::is_transient_database_error(&e) => {
+                return Err((StatusCode::SERVICE_UNAVAILABLE, e.to_string()));
+            }
+            Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        };
+
+    let count = |status| {
+        results
+            .iter()
+            .filter(|result| result.status == status)
+            .count()
+    };
+    let response = IngestResponse {
+        stored: count(IngestStatus::Stored),
+        duplicates: count(IngestStatus::Duplicate),
+        rejected: count(IngestStatus::Rejected),
+        results,
+    };
+
+    // Only fail the request as a whole when nothing in it could be used
+    let status = if response.rejected > 0 && response.rejected == response.results.len() {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::OK
+    };
+
+    Ok((status, Json(response)))
+}
+
 // User routes handlers
 pub async fn create_user_handler(
     State(state): State<AppState>,
     Json(payload): Json<CreateAppUser>,
 ) -> Result<Json<UserResponse>, (StatusCode, String)> {
+    if let Some(timezone) = &payload.timezone {
+        validate_timezone(&state, timezone).await?;
+    }
+
     match create_user(&state.db, payload).await {
         Ok(user) => Ok(Json(UserResponse::from(user))),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
@@ -158,6 +1344,10 @@ pub async fn update_user_handler(
         ));
     }
 
+    if let Some(timezone) = &payload.timezone {
+        validate_timezone(&state, timezone).await?;
+    }
+
     match update_user(&state.db, id, payload).await {
         Ok(Some(user)) => Ok(Json(UserResponse::from(user))),
         Ok(None) => Err((StatusCode::NOT_FOUND, "User not found".to_string())),
@@ -194,6 +1384,8 @@ pub fn create_router(state: AppState) -> Router {
     // Public routes that don't require authentication
     let public_routes = Router::new()
         .route("/", get(root))
+        .route("/health", get(health))
+        .route("/ingest", post(ingest_readings))
         .route("/login", post(login_handler))
         .route("/users", post(create_user_handler));
 
@@ -202,12 +1394,69 @@ pub fn create_router(state: AppState) -> Router {
         .route("/profile", get(get_profile))
         .route("/entries", get(get_entries))
         .route("/averages", get(get_averages))
+        .route("/readings", get(get_readings))
+        .route("/export", get(export_readings))
+        .route("/import", post(import_readings))
+        .route("/sensors/latest", get(get_latest_readings))
+        .route(
+            "/sensors/{unique_identifier}/completeness",
+            get(get_sensor_completeness),
+        )
+        .route(
+            "/sensors/{unique_identifier}/mold-risk",
+            get(get_sensor_mold_risk),
+        )
+        .route("/weather", get(get_weather))
+        .route("/weather/location", put(set_weather_location))
+        .route("/weather/location", delete(delete_weather_location))
+        .route("/ventilation", get(get_ventilation))
+        .route("/stream", get(stream_readings))
         .route("/mappings", get(get_all_mappings))
         .route("/mappings", post(create_mapping))
         .route("/mappings/{id}", get(get_mapping))
         .route("/mappings/{id}", put(update_mapping))
         .route("/mappings/{id}", delete(delete_mapping_handler))
         .route("/users/{id}", put(update_user_handler))
+        .route("/devices", get(get_devices))
+        .route("/devices/claim", post(claim_device))
+        .route(
+            "/devices/{unique_identifier}/transfer",
+            post(transfer_device),
+        )
+        .route("/alerts", get(get_active_alerts))
+        .route("/alerts/history", get(get_alert_history))
+        .route("/alerts/rules", get(get_alert_rules))
+        .route("/alerts/rules", post(create_alert_rule))
+        .route("/alerts/rules/{id}", get(get_alert_rule))
+        .route("/alerts/rules/{id}", put(update_alert_rule))
+        .route("/alerts/rules/{id}", delete(delete_alert_rule))
+        .route("/alerts/rules/{id}/channels", get(get_alert_rule_channels))
+        .route("/alerts/rules/{id}/channels", put(set_alert_rule_channels))
+        .route("/notifications/channels", get(get_notification_channels))
+        .route("/notifications/channels", post(create_notification_channel))
+        .route(
+            "/notifications/channels/{id}",
+            get(get_notification_channel),
+        )
+        .route(
+            "/notifications/channels/{id}",
+            put(update_notification_channel),
+        )
+        .route(
+            "/notifications/channels/{id}",
+            delete(delete_notification_channel),
+        )
+        .route(
+            "/notifications/channels/{id}/test",
+            post(test_notification_channel),
+        )
+        .route(
+            "/notifications/deliveries",
+            get(get_notification_deliveries),
+        )
+        .route("/rejected", get(get_rejected_messages))
+        .route("/rejected/{id}", get(get_rejected_message))
+        .route("/rejected/{id}/replay", post(replay_rejected_message))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
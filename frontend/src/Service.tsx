@@ -36,7 +36,12 @@ export const registerUser = async (username: string, password: string) => {
     const response = await fetch(`${API_BASE_URL}/users`, {
       method: "POST",
       headers: { "Content-Type": "application/json" },
-      body: JSON.stringify({ username, password }),
+      body: JSON.stringify({
+        username,
+        password,
+        // Daily averages are cut at midnight in this timezone
+        timezone: Intl.DateTimeFormat().resolvedOptions().timeZone,
+      }),
     });
     if (!response.ok) {
       throw new Error(`HTTP error! status: ${response.status}`);
@@ -82,9 +87,26 @@ export const getSensors = async () => {
   }
 };
 
-export const mapSensor = async (unique_identifier: string, label: string) => {
+export const mapSensor = async (
+  unique_identifier: string,
+  label: string,
+  claim_code: string
+) => {
   if (!isAuthenticated()) throw new Error("User not authenticated");
   try {
+    // Prove ownership with the claim code before the sensor can be mapped
+    const claimResponse = await fetchWithRetry(`${API_BASE_URL}/devices/claim`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json", ...getAuthHeaders() },
+      body: JSON.stringify({
+        unique_identifier,
+        claim_code,
+      }),
+    });
+    if (!claimResponse.ok) {
+      throw new Error(`HTTP error! status: ${claimResponse.status}`);
+    }
+
     const response = await fetchWithRetry(`${API_BASE_URL}/mappings`, {
       method: "POST",
       headers: { "Content-Type": "application/json", ...getAuthHeaders() },
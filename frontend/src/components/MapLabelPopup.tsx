@@ -1,3 +1,4 @@
+import { useState } from "react";
 import { mapSensor } from "../Service";
 
 const MapLabelPopup = ({
@@ -10,10 +11,16 @@ const MapLabelPopup = ({
   availableSensors,
   setAvailableSensors,
 }: any) => {
+  const [claimCode, setClaimCode] = useState("");
+
   const handleMapLabelSubmit = async () => {
     if (!mappingSensorId) return;
     try {
-      const response = await mapSensor(mappingSensorId, mappingSensorLabel);
+      const response = await mapSensor(
+        mappingSensorId,
+        mappingSensorLabel,
+        claimCode
+      );
 
       const mappedSensor = availableSensors.find(
         (s: any) => s.unique_identifier === mappingSensorId
@@ -49,6 +56,13 @@ const MapLabelPopup = ({
           value={mappingSensorLabel}
           onChange={(e) => setMappingSensorLabel(e.target.value)}
         />
+        <h3>Enter Claim Code</h3>
+        <input
+          type="text"
+          placeholder="XXXX-XXXX"
+          value={claimCode}
+          onChange={(e) => setClaimCode(e.target.value)}
+        />
         <button onClick={handleMapLabelSubmit}>Save</button>
         <button
           onClick={() => {